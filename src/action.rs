@@ -0,0 +1,110 @@
+use crate::ndef::Record;
+use std::str;
+use tokio::time::Duration;
+use tracing::error;
+use url::Url;
+
+// Action cards carry a command for the box instead of something to play, either as a
+// `drempelbox:` URI, e.g. `drempelbox:sleep?minutes=20`, or as an external record whose
+// payload is the part after the scheme.
+
+pub const SCHEME: &str = "drempelbox";
+pub const RECORD_TYPE: &str = "drempelbox.org:action";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Volume between 0 and 1, written as a percentage on the card
+    VolumeSet(f64),
+    /// Stops playback once the time is up
+    SleepTimer(Duration),
+    ShuffleToggle,
+    Shutdown,
+    /// Stops playback, including anything paused
+    StopAll,
+    NextTrack,
+}
+
+impl Action {
+    pub fn from_url(url: &Url) -> Option<Self> {
+        if url.scheme() != SCHEME {
+            return None;
+        }
+        let argument = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .and_then(|(_, value)| value.parse::<u64>().ok())
+        };
+
+        let action = match url.path() {
+            "volume" => {
+                argument("level").map(|level| Action::VolumeSet(level.min(100) as f64 / 100.0))
+            }
+            "sleep" => argument("minutes")
+                .map(|minutes| Action::SleepTimer(Duration::from_secs(minutes * 60))),
+            "shuffle" => Some(Action::ShuffleToggle),
+            "shutdown" => Some(Action::Shutdown),
+            "stop" => Some(Action::StopAll),
+            "next" => Some(Action::NextTrack),
+            _ => None,
+        };
+        if action.is_none() {
+            let url = url.to_string();
+            error!(url, "invalid action");
+        }
+        action
+    }
+
+    /// The `drempelbox:` URI equivalent to an action record.
+    pub fn url_from_record(record: &Record) -> Option<Url> {
+        match record {
+            Record::External {
+                record_type,
+                payload,
+            } if record_type == RECORD_TYPE => {
+                let action = str::from_utf8(payload).ok()?;
+                Url::parse(&format!("{SCHEME}:{action}")).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::action::*;
+
+    fn parse(url: &str) -> Option<Action> {
+        Action::from_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn parse_actions() {
+        assert_eq!(
+            parse("drempelbox:volume?level=30"),
+            Some(Action::VolumeSet(0.3))
+        );
+        assert_eq!(
+            parse("drempelbox:volume?level=250"),
+            Some(Action::VolumeSet(1.0))
+        );
+        assert_eq!(
+            parse("drempelbox:sleep?minutes=20"),
+            Some(Action::SleepTimer(Duration::from_secs(1200)))
+        );
+        assert_eq!(parse("drempelbox:shuffle"), Some(Action::ShuffleToggle));
+        assert_eq!(parse("drempelbox:next"), Some(Action::NextTrack));
+        assert_eq!(parse("drempelbox:volume"), None);
+        assert_eq!(parse("drempelbox:dance"), None);
+        assert_eq!(
+            parse("https://open.spotify.com/track/4abJbqX8C8CQTXHZxEbJZz"),
+            None
+        );
+
+        let record = Record::External {
+            record_type: String::from(RECORD_TYPE),
+            payload: b"stop".to_vec(),
+        };
+        let url = Action::url_from_record(&record).unwrap();
+        assert_eq!(Action::from_url(&url), Some(Action::StopAll));
+    }
+}
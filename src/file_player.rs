@@ -1,12 +1,14 @@
 use async_std::sync::Arc;
 use librespot::playback::mixer::VolumeGetter;
-use rodio::{Decoder, OutputStream, Sink};
+use rodio::{Decoder, OutputStream, Sink, Source};
 use std::fs::File;
 use std::io::BufReader;
+use std::time::Duration;
 use tokio::sync::Mutex; // this is more expensive than std::sync::Mutex but makes using it across awaits easier
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::player::Mixer;
+use crate::positions::Position;
 
 pub struct FilePlayer {
     sink: Arc<Mutex<Sink>>,
@@ -33,6 +35,8 @@ impl FilePlayer {
         &self,
         file_path: String,
         play_immediately: bool,
+        start: Position,
+        repeat: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
         info!(file_path, "attempting to open file");
 
@@ -49,8 +53,17 @@ impl FilePlayer {
             sink.clear();
         }
 
-        info!(file_path, "Appending to sink queue");
-        sink.append(source);
+        info!(file_path, repeat, "Appending to sink queue");
+        match repeat {
+            true => sink.append(source.buffered().repeat_infinite()),
+            false => sink.append(source),
+        }
+        if start.offset_ms > 0 {
+            info!(start.offset_ms, "seeking to resume position");
+            if let Err(e) = sink.try_seek(Duration::from_millis(start.offset_ms)) {
+                warn!(%e, "couldn't seek to resume position");
+            }
+        }
         sink.play();
 
         Ok(())
@@ -62,6 +75,31 @@ impl FilePlayer {
         Ok(())
     }
 
+    /// Where playback currently is, `None` once the file has finished.
+    pub async fn position(&self) -> Option<Position> {
+        let sink = self.sink.lock().await;
+        match sink.empty() {
+            true => None,
+            false => Some(Position {
+                track: 0,
+                offset_ms: sink.get_pos().as_millis() as u64,
+            }),
+        }
+    }
+
+    /// Keeps the queue and position, so `resume` picks up where playback stopped.
+    pub async fn pause(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let sink = self.sink.lock().await;
+        sink.pause();
+        Ok(())
+    }
+
+    pub async fn resume(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let sink = self.sink.lock().await;
+        sink.play();
+        Ok(())
+    }
+
     pub async fn volume_changed(&self) {
         // TODO: we could use some observer pattern here instead
         let sink = self.sink.lock().await;
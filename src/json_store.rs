@@ -0,0 +1,85 @@
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+// Small JSON files in the cache directory, for state that has to survive a restart.
+
+/// Where everything we keep around lives.
+pub fn cache_directory() -> String {
+    // we shouldn't need the default here, as systemd should export CACHE_DIRECTORY,
+    // but for some reason it is not seen by our process
+    env::var("CACHE_DIRECTORY").unwrap_or(String::from("/var/cache/drempelbox"))
+}
+
+pub fn cache_path(file_name: &str) -> PathBuf {
+    Path::new(&cache_directory()).join(file_name)
+}
+
+/// Reads the file, starting from the default if it doesn't exist yet or can't be used.
+pub async fn load<T: DeserializeOwned + Default>(path: &Path) -> T {
+    match tokio::fs::read(path).await {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!(%e, ?path, "ignoring invalid JSON file");
+                T::default()
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => T::default(),
+        Err(e) => {
+            warn!(%e, ?path, "couldn't read JSON file");
+            T::default()
+        }
+    }
+}
+
+pub async fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let data = serde_json::to_vec_pretty(value)?;
+
+    // write next to the real file and rename, so a power cut can't leave half a file behind.
+    // Without the syncs the rename may hit the disk before the data does.
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp_path, path).await?;
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::File::open(parent).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json_store::*;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn save_and_load() {
+        let dir = env::temp_dir().join(format!("drempelbox-json-store-{}", std::process::id()));
+        let path = dir.join("store.json");
+        let value = BTreeMap::from([(String::from("048a3b125c6180"), 42u64)]);
+
+        let missing: BTreeMap<String, u64> = load(&path).await;
+        assert!(missing.is_empty());
+
+        save(&path, &value).await.unwrap();
+        assert_eq!(load::<BTreeMap<String, u64>>(&path).await, value);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        std::fs::write(&path, b"{ not json").unwrap();
+        assert!(load::<BTreeMap<String, u64>>(&path).await.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,12 +1,20 @@
 use std::time::Duration;
 
-use tokio::sync::mpsc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinSet;
 use tokio::time::sleep;
-use tracing::error;
+use tracing::{error, info};
 
+pub mod mfrc522;
 pub mod ndef;
-pub mod ntag215;
+pub mod ntag21x;
+pub mod originality;
+pub mod pn532;
+pub mod reader;
+use crate::reader::ReaderHealth;
+#[cfg(test)]
+mod simulated_reader;
 
 pub mod file_player;
 pub mod spotify_player;
@@ -15,13 +23,21 @@ pub mod server;
 use crate::server::{start_server_task, AppState};
 
 pub mod ntag;
-use crate::ntag::start_ntag_reader_task;
+use crate::ntag::{start_ntag_reader_task, TagRequestMessage};
 
+pub mod json_store;
+pub mod playback_options;
 pub mod player;
+pub mod positions;
 use crate::player::{start_player_task, PlayerRequestMessage};
 
-pub mod tuple_windows;
+pub mod presence;
 
+pub mod tag_events;
+pub mod tag_mappings;
+use crate::tag_mappings::TagMappings;
+
+pub mod action;
 pub mod amp;
 use crate::amp::Amp;
 
@@ -49,23 +65,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _shutdown = Shutdown::new(&mut join_set).await?;
 
     let (sender, receiver) = mpsc::channel::<PlayerRequestMessage>(16);
-    let app_state = AppState { sender, amp, led };
+    let (tag_sender, tag_receiver) = mpsc::channel::<TagRequestMessage>(16);
+    let (reader_health_sender, reader_health) = watch::channel(ReaderHealth::default());
+    let (tag_info_sender, tag_info) = watch::channel(None);
+    let mappings = TagMappings::load().await;
+    let app_state = AppState {
+        sender,
+        tag_sender,
+        reader_health,
+        tag_info,
+        tag_events: tag_events::channel(),
+        mappings,
+        amp,
+        led,
+    };
 
     let _volume_button = VolumeButtons::new(app_state.clone().sender)?;
 
     start_player_task(&mut join_set, receiver, amp_player).await?;
-    start_ntag_reader_task(&mut join_set, app_state.clone()).await;
+    start_ntag_reader_task(
+        &mut join_set,
+        app_state.clone(),
+        tag_receiver,
+        reader_health_sender,
+        tag_info_sender,
+    )
+    .await;
     start_server_task(&mut join_set, app_state.clone()).await;
 
     app_state.amp.power_on().await?;
     sleep(Duration::from_millis(250)).await;
     app_state.led.on().await?;
 
-    while let Some(_res) = join_set.join_next().await {
-        let err = _res.err().unwrap().to_string();
-        error!(err, "Task finished unexpectedly!");
-        // TODO: we should probably crash the app at this point
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            res = join_set.join_next() => match res {
+                Some(_res) => {
+                    let err = _res.err().unwrap().to_string();
+                    error!(err, "Task finished unexpectedly!");
+                    // TODO: we should probably crash the app at this point
+                }
+                None => break,
+            },
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
     }
 
+    info!("shutting down");
+    let (sender, receiver) = oneshot::channel::<()>();
+    match app_state
+        .sender
+        .send(PlayerRequestMessage::Shutdown { responder: sender })
+        .await
+    {
+        Ok(_) => {
+            if receiver.await.is_err() {
+                error!("player didn't confirm shutdown");
+            }
+        }
+        Err(e) => error!("error submitting shutdown request: {e}"),
+    };
+
     Ok(())
 }
@@ -0,0 +1,542 @@
+use rppal::gpio::OutputPin;
+use rppal::spi::Spi;
+use std::time::{Duration, Instant};
+
+use crate::reader::{AtqA, Error, Reader, Uid};
+
+// Minimal MFRC522 driver. The mfrc522 crate keeps its transceive private and only offers the
+// MIFARE Classic commands, which leaves no way to send NTAG specific commands like WRITE.
+
+#[allow(dead_code)]
+mod register {
+    pub const COMMAND: u8 = 0x01;
+    pub const COM_IEN: u8 = 0x02;
+    pub const DIV_IEN: u8 = 0x03;
+    pub const COM_IRQ: u8 = 0x04;
+    pub const DIV_IRQ: u8 = 0x05;
+    pub const ERROR: u8 = 0x06;
+    pub const STATUS_1: u8 = 0x07;
+    pub const STATUS_2: u8 = 0x08;
+    pub const FIFO_DATA: u8 = 0x09;
+    pub const FIFO_LEVEL: u8 = 0x0a;
+    pub const CONTROL: u8 = 0x0c;
+    pub const BIT_FRAMING: u8 = 0x0d;
+    pub const COLL: u8 = 0x0e;
+    pub const MODE: u8 = 0x11;
+    pub const TX_MODE: u8 = 0x12;
+    pub const RX_MODE: u8 = 0x13;
+    pub const TX_CONTROL: u8 = 0x14;
+    pub const TX_ASK: u8 = 0x15;
+    pub const CRC_RESULT_HIGH: u8 = 0x21;
+    pub const CRC_RESULT_LOW: u8 = 0x22;
+    pub const MOD_WIDTH: u8 = 0x24;
+    pub const T_MODE: u8 = 0x2a;
+    pub const T_PRESCALER: u8 = 0x2b;
+    pub const T_RELOAD_HIGH: u8 = 0x2c;
+    pub const T_RELOAD_LOW: u8 = 0x2d;
+    pub const VERSION: u8 = 0x37;
+}
+
+#[allow(dead_code)]
+mod command {
+    pub const IDLE: u8 = 0b0000;
+    pub const CALC_CRC: u8 = 0b0011;
+    pub const TRANSCEIVE: u8 = 0b1100;
+    pub const SOFT_RESET: u8 = 0b1111;
+}
+
+#[allow(dead_code)]
+mod picc {
+    pub const REQA: u8 = 0x26;
+    pub const WUPA: u8 = 0x52;
+    pub const HLTA: u8 = 0x50;
+    pub const SEL_CL1: u8 = 0x93;
+    pub const SEL_CL2: u8 = 0x95;
+    pub const SEL_CL3: u8 = 0x97;
+
+    /// 4 bit ACK sent by the PICC after a successful write
+    pub const ACK: u8 = 0x0a;
+}
+
+const POWER_DOWN: u8 = 1 << 4;
+
+const TIMER_IRQ: u8 = 1 << 0;
+const ERR_IRQ: u8 = 1 << 1;
+const IDLE_IRQ: u8 = 1 << 4;
+const RX_IRQ: u8 = 1 << 5;
+const CRC_IRQ: u8 = 1 << 2;
+
+const PROTOCOL_ERR: u8 = 1 << 0;
+const PARITY_ERR: u8 = 1 << 1;
+const CRC_ERR: u8 = 1 << 2;
+const COLL_ERR: u8 = 1 << 3;
+const BUFFER_OVFL: u8 = 1 << 4;
+const TEMP_ERR: u8 = 1 << 6;
+const WR_ERR: u8 = 1 << 7;
+
+// in ComIEnReg, drives the IRQ pin low while an interrupt is pending
+const IRQ_INV: u8 = 1 << 7;
+// in DivIEnReg, without it the IRQ pin is open drain
+const IRQ_PUSH_PULL: u8 = 1 << 7;
+
+const FLUSH_BUFFER: u8 = 1 << 7;
+const FORCE_100_ASK: u8 = 1 << 6;
+
+const FIFO_SIZE: usize = 64;
+
+/// Versions reported by genuine MFRC522s, 1.0 and 2.0
+const KNOWN_VERSIONS: [u8; 2] = [0x91, 0x92];
+
+/// Well past the 25ms receive timeout, if the chip hasn't said anything by then it is stuck
+const CHIP_TIMEOUT: Duration = Duration::from_millis(100);
+const RESET_PULSE: Duration = Duration::from_micros(50);
+
+struct FifoData {
+    buffer: [u8; FIFO_SIZE],
+    valid_bytes: usize,
+    valid_bits: usize,
+}
+
+impl FifoData {
+    fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.valid_bytes]
+    }
+
+    /// Appends the received bits to the `dst_valid_bits` bits already known in `dst`.
+    // Adapted from `FifoData::copy_bits_to` in the mfrc522 crate 0.6.1,
+    // https://github.com/jspngh/rfid-rs, licensed MIT OR Apache-2.0.
+    fn copy_bits_to(&self, dst: &mut [u8], dst_valid_bits: u8) {
+        if self.valid_bytes == 0 {
+            return;
+        }
+
+        let mask: u8 = 0xff << (dst_valid_bits % 8);
+        let mut index = usize::from(dst_valid_bits / 8);
+        dst[index] = (self.buffer[0] & mask) | (dst[index] & !mask);
+        index += 1;
+
+        let len = self.valid_bytes - 1;
+        if len > 0 {
+            dst[index..index + len].copy_from_slice(&self.buffer[1..=len]);
+        }
+    }
+}
+
+pub struct Mfrc522 {
+    spi: Spi,
+    reset_pin: Option<OutputPin>,
+    card_detect_irq: bool,
+}
+
+impl Mfrc522 {
+    /// Resets and configures the chip, leaving the antenna switched on. With a reset pin the
+    /// chip gets a hard reset, which also gets it out of states a soft reset can't.
+    pub fn new(spi: Spi, reset_pin: Option<OutputPin>) -> Result<Self, Error> {
+        let mut mfrc522 = Self {
+            spi,
+            reset_pin,
+            card_detect_irq: false,
+        };
+        mfrc522.hard_reset();
+        mfrc522.init()?;
+        Ok(mfrc522)
+    }
+
+    fn hard_reset(&mut self) {
+        if let Some(reset_pin) = &mut self.reset_pin {
+            reset_pin.set_low();
+            std::thread::sleep(RESET_PULSE);
+            reset_pin.set_high();
+            std::thread::sleep(RESET_PULSE);
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        self.command(command::SOFT_RESET)?;
+        let start = Instant::now();
+        while self.read(register::COMMAND)? & POWER_DOWN != 0 {
+            if start.elapsed() > CHIP_TIMEOUT {
+                return Err(Error::Unresponsive);
+            }
+        }
+
+        self.write(register::TX_MODE, 0x00)?;
+        self.write(register::RX_MODE, 0x00)?;
+        self.write(register::MOD_WIDTH, 0x26)?;
+
+        // start the timer automatically at the end of each transmission, with a 40kHz timer
+        // frequency and a reload value of 1000 this gives us a 25ms timeout
+        self.write(register::T_MODE, 0x80)?;
+        self.write(register::T_PRESCALER, 0xa9)?;
+        self.write(register::T_RELOAD_HIGH, 0x03)?;
+        self.write(register::T_RELOAD_LOW, 0xe8)?;
+
+        self.write(register::TX_ASK, FORCE_100_ASK)?;
+        // CRC preset value according to ISO 14443-3 part 6.2.4
+        self.write(register::MODE, (0x3f & !0b11) | 0b01)?;
+        // enable the antenna
+        self.rmw(register::TX_CONTROL, |b| b | 0b11)?;
+
+        if self.card_detect_irq {
+            self.enable_card_detect_irq()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn version(&mut self) -> Result<u8, Error> {
+        self.read(register::VERSION)
+    }
+
+    fn request(&mut self, request: u8) -> Result<AtqA, Error> {
+        // REQA and WUPA are short frames of 7 bits
+        let fifo_data = self.transceive(&[request], 7, 0)?;
+        match fifo_data.as_bytes() {
+            [atqa_0, atqa_1] if fifo_data.valid_bits == 0 => Ok(AtqA::new([*atqa_0, *atqa_1])),
+            _ => Err(Error::IncompleteFrame),
+        }
+    }
+
+    /// Routes RxIRq to the IRQ pin, active low, so a card answering `arm_card_detect`
+    /// pulls the pin down.
+    pub fn enable_card_detect_irq(&mut self) -> Result<(), Error> {
+        // remembered so a reset doesn't silently turn card detection off
+        self.card_detect_irq = true;
+        self.write(register::DIV_IEN, IRQ_PUSH_PULL)?;
+        self.write(register::COM_IEN, IRQ_INV | RX_IRQ)?;
+        self.clear_irq()
+    }
+
+    pub fn disable_irq(&mut self) -> Result<(), Error> {
+        self.card_detect_irq = false;
+        self.write(register::COM_IEN, 0)?;
+        self.write(register::DIV_IEN, 0)?;
+        self.clear_irq()
+    }
+
+    /// Clears all pending interrupts, releasing the IRQ pin.
+    pub fn clear_irq(&mut self) -> Result<(), Error> {
+        self.write(register::COM_IRQ, 0x7f)?;
+        self.write(register::DIV_IRQ, 0x7f)
+    }
+
+    /// Holds the IRQ pin asserted through the CRC coprocessor until `clear_irq`, which lets
+    /// the host check the pin is wired up without a card in the field.
+    pub fn assert_test_irq(&mut self) -> Result<(), Error> {
+        self.write(register::COM_IEN, IRQ_INV)?;
+        self.write(register::DIV_IEN, IRQ_PUSH_PULL | CRC_IRQ)?;
+        self.calculate_crc(&[0])?;
+        // calculate_crc leaves CRCIRq set, which is what keeps the pin asserted
+        Ok(())
+    }
+
+    fn calculate_crc(&mut self, data: &[u8]) -> Result<[u8; 2], Error> {
+        self.command(command::IDLE)?;
+        self.write(register::DIV_IRQ, CRC_IRQ)?;
+        self.write(register::FIFO_LEVEL, FLUSH_BUFFER)?;
+        self.write_many(register::FIFO_DATA, data)?;
+        self.command(command::CALC_CRC)?;
+
+        let start = Instant::now();
+        while start.elapsed() < CHIP_TIMEOUT {
+            if self.read(register::DIV_IRQ)? & CRC_IRQ != 0 {
+                self.command(command::IDLE)?;
+                return Ok([
+                    self.read(register::CRC_RESULT_LOW)?,
+                    self.read(register::CRC_RESULT_HIGH)?,
+                ]);
+            }
+        }
+
+        Err(Error::Unresponsive)
+    }
+
+    fn check_error_register(&mut self) -> Result<(), Error> {
+        let error = self.read(register::ERROR)?;
+
+        if error & PROTOCOL_ERR != 0 {
+            Err(Error::Protocol)
+        } else if error & PARITY_ERR != 0 {
+            Err(Error::Parity)
+        } else if error & CRC_ERR != 0 {
+            Err(Error::Crc)
+        } else if error & COLL_ERR != 0 {
+            Err(Error::Collision)
+        } else if error & BUFFER_OVFL != 0 {
+            Err(Error::BufferOverflow)
+        } else if error & TEMP_ERR != 0 {
+            Err(Error::Overheating)
+        } else if error & WR_ERR != 0 {
+            Err(Error::Wr)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn transceive(
+        &mut self,
+        tx: &[u8],
+        tx_last_bits: u8,
+        rx_align_bits: u8,
+    ) -> Result<FifoData, Error> {
+        self.command(command::IDLE)?;
+        self.write(register::COM_IRQ, 0x7f)?;
+        self.write(register::FIFO_LEVEL, FLUSH_BUFFER)?;
+        self.write_many(register::FIFO_DATA, tx)?;
+        self.command(command::TRANSCEIVE)?;
+
+        // StartSend, plus the framing for short frames and anticollision
+        self.write(
+            register::BIT_FRAMING,
+            (1 << 7) | ((rx_align_bits & 0b0111) << 4) | (tx_last_bits & 0b0111),
+        )?;
+
+        let start = Instant::now();
+        loop {
+            let irq = self.read(register::COM_IRQ)?;
+
+            if irq & (RX_IRQ | ERR_IRQ | IDLE_IRQ) != 0 {
+                break;
+            } else if irq & TIMER_IRQ != 0 {
+                return Err(Error::Timeout);
+            } else if start.elapsed() > CHIP_TIMEOUT {
+                // the timer should have fired long ago
+                return Err(Error::Unresponsive);
+            }
+        }
+
+        self.check_error_register()?;
+        self.fifo_data()
+    }
+
+    fn fifo_data(&mut self) -> Result<FifoData, Error> {
+        let mut buffer = [0u8; FIFO_SIZE];
+        let valid_bytes = usize::from(self.read(register::FIFO_LEVEL)?).min(FIFO_SIZE);
+        let mut valid_bits = 0;
+
+        if valid_bytes > 0 {
+            self.read_many(register::FIFO_DATA, &mut buffer[..valid_bytes])?;
+            valid_bits = usize::from(self.read(register::CONTROL)? & 0x07);
+        }
+
+        Ok(FifoData {
+            buffer,
+            valid_bytes,
+            valid_bits,
+        })
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Error> {
+        self.write(register::COMMAND, command)
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, Error> {
+        let mut buffer = [0u8; 2];
+        self.spi
+            .transfer(&mut buffer, &[(register << 1) | 0x80, 0])?;
+        delay();
+        Ok(buffer[1])
+    }
+
+    fn read_many(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        // the address is sent once per byte, the response trails by one byte
+        let mut tx = vec![(register << 1) | 0x80; buffer.len()];
+        tx.push(0);
+        let mut rx = vec![0u8; tx.len()];
+        self.spi.transfer(&mut rx, &tx)?;
+        delay();
+        buffer.copy_from_slice(&rx[1..]);
+        Ok(())
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), Error> {
+        self.spi.write(&[register << 1, value])?;
+        delay();
+        Ok(())
+    }
+
+    fn write_many(&mut self, register: u8, values: &[u8]) -> Result<(), Error> {
+        let mut tx = Vec::with_capacity(values.len() + 1);
+        tx.push(register << 1);
+        tx.extend(values);
+        self.spi.write(&tx)?;
+        delay();
+        Ok(())
+    }
+
+    fn rmw(&mut self, register: u8, f: impl FnOnce(u8) -> u8) -> Result<(), Error> {
+        let value = self.read(register)?;
+        self.write(register, f(value))
+    }
+}
+
+impl Reader for Mfrc522 {
+    fn reqa(&mut self) -> Result<AtqA, Error> {
+        self.request(picc::REQA)
+    }
+
+    fn wupa(&mut self) -> Result<AtqA, Error> {
+        self.request(picc::WUPA)
+    }
+
+    fn hlta(&mut self) -> Result<(), Error> {
+        // any response within 1ms after HLTA is to be interpreted as NAK,
+        // so only a timeout means success
+        match self.transceive_crc(&[picc::HLTA, 0]) {
+            Err(Error::Timeout) => Ok(()),
+            Ok(_) => Err(Error::Nak(0)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Adapted from the anticollision loop of `Mfrc522::select` in the mfrc522 crate 0.6.1,
+    // https://github.com/jspngh/rfid-rs, licensed MIT OR Apache-2.0.
+    fn select(&mut self, atqa: &AtqA) -> Result<Uid, Error> {
+        // check for proprietary anticollision
+        if (atqa.as_bytes()[0] & 0b00011111).count_ones() != 1 {
+            return Err(Error::Proprietary);
+        }
+
+        // clear ValuesAfterColl
+        self.rmw(register::COLL, |b| b & !0x80)?;
+
+        let mut uid_bytes = Vec::with_capacity(10);
+
+        for select_command in [picc::SEL_CL1, picc::SEL_CL2, picc::SEL_CL3] {
+            let mut known_bits: u8 = 0;
+            let mut tx = [0u8; 7];
+            tx[0] = select_command;
+
+            loop {
+                let tx_last_bits = known_bits % 8;
+                let tx_bytes = 2 + known_bits / 8;
+                let end = usize::from(tx_bytes) + usize::from(tx_last_bits > 0);
+                tx[1] = (tx_bytes << 4) + tx_last_bits;
+
+                // only send the known bits of the last byte, and align the received bits to them
+                match self.transceive(&tx[..end], tx_last_bits, tx_last_bits) {
+                    Ok(fifo_data) => {
+                        fifo_data.copy_bits_to(&mut tx[2..], known_bits);
+                        break;
+                    }
+                    Err(Error::Collision) => {
+                        let coll = self.read(register::COLL)?;
+                        if coll & (1 << 5) != 0 {
+                            // CollPosNotValid
+                            return Err(Error::Collision);
+                        }
+                        let coll_pos = match coll & 0x1f {
+                            0 => 32,
+                            coll_pos => coll_pos,
+                        };
+                        if coll_pos < known_bits {
+                            return Err(Error::Collision);
+                        }
+                        let fifo_data = self.fifo_data()?;
+                        fifo_data.copy_bits_to(&mut tx[2..], known_bits);
+                        known_bits = coll_pos;
+
+                        // pick the PICC that has a 1 at the collision position
+                        let index = 1
+                            + usize::from(known_bits / 8)
+                            + usize::from(!known_bits.is_multiple_of(8));
+                        tx[index] |= 1 << ((known_bits - 1) % 8);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            // NVB: 7 valid bytes, then BCC
+            tx[1] = 0x70;
+            tx[6] = tx[2] ^ tx[3] ^ tx[4] ^ tx[5];
+
+            let sak = match self.transceive_crc(&tx)?.as_slice() {
+                [sak] => *sak,
+                _ => return Err(Error::IncompleteFrame),
+            };
+
+            // the SAK tells us whether there is another cascade level,
+            // in which case the first byte is the cascade tag
+            if sak & (1 << 2) != 0 {
+                uid_bytes.extend(&tx[3..6]);
+            } else {
+                uid_bytes.extend(&tx[2..6]);
+                return Ok(Uid::new(uid_bytes, sak));
+            }
+        }
+
+        Err(Error::IncompleteFrame)
+    }
+
+    fn transceive_crc(&mut self, tx: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut tx = tx.to_vec();
+        let crc = self.calculate_crc(&tx)?;
+        tx.extend(crc);
+
+        let fifo_data = self.transceive(&tx, 0, 0)?;
+
+        // a short frame at this point is a NAK
+        if fifo_data.valid_bits != 0 {
+            return match fifo_data.as_bytes() {
+                [nak] => Err(Error::Nak(*nak)),
+                _ => Err(Error::IncompleteFrame),
+            };
+        }
+
+        match fifo_data.as_bytes() {
+            [] => Ok(Vec::new()),
+            [data @ .., crc_0, crc_1] => {
+                if self.calculate_crc(data)? != [*crc_0, *crc_1] {
+                    return Err(Error::Crc);
+                }
+                Ok(data.to_vec())
+            }
+            _ => Err(Error::IncompleteFrame),
+        }
+    }
+
+    fn transceive_ack(&mut self, tx: &[u8]) -> Result<(), Error> {
+        let mut tx = tx.to_vec();
+        let crc = self.calculate_crc(&tx)?;
+        tx.extend(crc);
+
+        let fifo_data = self.transceive(&tx, 0, 0)?;
+        match fifo_data.as_bytes() {
+            [response] if fifo_data.valid_bits == 4 => match response & 0x0f {
+                picc::ACK => Ok(()),
+                nak => Err(Error::Nak(nak)),
+            },
+            _ => Err(Error::IncompleteFrame),
+        }
+    }
+
+    fn health_check(&mut self) -> Result<(), Error> {
+        match self.version()? {
+            version if KNOWN_VERSIONS.contains(&version) => Ok(()),
+            version => Err(Error::UnknownVersion(version)),
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.hard_reset();
+        self.init()?;
+        self.health_check()
+    }
+
+    /// Sends a single REQA and returns without waiting for the answer. The chip can't repeat
+    /// this on its own, so it needs to be armed again once its receive timeout passed.
+    fn arm_card_detect(&mut self) -> Result<(), Error> {
+        self.command(command::IDLE)?;
+        self.clear_irq()?;
+        self.write(register::FIFO_LEVEL, FLUSH_BUFFER)?;
+        self.write(register::FIFO_DATA, picc::REQA)?;
+        self.command(command::TRANSCEIVE)?;
+        // StartSend, REQA is a short frame of 7 bits
+        self.write(register::BIT_FRAMING, (1 << 7) | 7)
+    }
+}
+
+/// NSS needs to be high for at least 50ns between transfers.
+fn delay() {
+    std::thread::sleep(Duration::from_nanos(50));
+}
@@ -1,8 +1,10 @@
 use bitflags::bitflags;
+use serde::Serialize;
 use std::str;
 use tracing::{debug, error};
 
 bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct Flags: u8 {
         const MESSAGE_BEGIN = 0b10000000;
         const MESSAGE_END = 0b01000000;
@@ -59,8 +61,34 @@ const PREFIX_STRINGS: &[&str] = &[
     "urn:nfc:",
 ];
 
+impl Flags {
+    const TNF_MASK: u8 = 0b00000111;
+
+    pub fn tnf(&self) -> Flags {
+        Flags::from_bits_truncate(self.bits() & Self::TNF_MASK)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum WellKnownType {
     URI,
+    Text,
+    SmartPoster,
+    Action,
+    Size,
+}
+
+impl WellKnownType {
+    pub fn from_type(record_type: &[u8]) -> Option<Self> {
+        match record_type {
+            b"U" => Some(WellKnownType::URI),
+            b"T" => Some(WellKnownType::Text),
+            b"Sp" => Some(WellKnownType::SmartPoster),
+            b"act" => Some(WellKnownType::Action),
+            b"s" => Some(WellKnownType::Size),
+            _ => None,
+        }
+    }
 }
 
 struct ByteGetter<'a> {
@@ -78,8 +106,9 @@ impl<'a> ByteGetter<'a> {
         }
     }
 
-    fn check_index(&self) -> Option<()> {
-        match self.len.unwrap_or(usize::MAX) > self.index {
+    fn check_index(&self, byte_count: usize) -> Option<()> {
+        let limit = self.len.unwrap_or(usize::MAX).min(self.data.len());
+        match self.index + byte_count <= limit {
             true => Some(()),
             false => {
                 error!("Trying to overread buffer!");
@@ -89,7 +118,7 @@ impl<'a> ByteGetter<'a> {
     }
 
     pub fn get_byte(&mut self) -> Option<u8> {
-        self.check_index()?;
+        self.check_index(1)?;
 
         let res = self.data[self.index];
         debug!("got byte {:02x?}", res);
@@ -98,7 +127,7 @@ impl<'a> ByteGetter<'a> {
     }
 
     pub fn get_bytes(&mut self, byte_count: usize) -> Option<&'a [u8]> {
-        self.check_index()?;
+        self.check_index(byte_count)?;
 
         let res = &self.data[self.index..self.index + byte_count];
         debug!("got bytes {:02x?}", res);
@@ -107,20 +136,83 @@ impl<'a> ByteGetter<'a> {
     }
 
     pub fn get_bytes_const<const N: usize>(&mut self) -> Option<[u8; N]> {
-        self.check_index()?;
-        let res: [u8; N] = self.data[self.index..self.index + N].try_into().unwrap();
+        self.check_index(N)?;
+        let res: [u8; N] = self.data[self.index..self.index + N].try_into().ok()?;
         self.index += N;
         Some(res)
     }
 
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    /// Limits all further reads to the next `len` bytes.
     pub fn set_len(&mut self, len: usize) {
-        self.len = Some(len);
+        self.len = Some(self.index + len);
+    }
+}
+
+/// TLV block types found in the data area of an NFC Forum Type 2 tag.
+mod tlv {
+    pub const NULL: u8 = 0x00;
+    pub const LOCK_CONTROL: u8 = 0x01;
+    pub const MEMORY_CONTROL: u8 = 0x02;
+    pub const NDEF_MESSAGE: u8 = 0x03;
+    pub const PROPRIETARY: u8 = 0xfd;
+    pub const TERMINATOR: u8 = 0xfe;
+
+    /// Marks the 3-byte length format, followed by a big endian u16.
+    pub const LONG_LENGTH_MARKER: u8 = 0xff;
+}
+
+/// Contents of a Lock Control or Memory Control TLV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlTlv {
+    pub page_address: u8,
+    pub byte_offset: u8,
+    /// Number of dynamic lock bits or reserved bytes
+    pub size: u16,
+    /// Bytes locked per lock bit as a power of two, only meaningful for lock control
+    pub bytes_locked_per_bit: u8,
+    /// Bytes per page as a power of two
+    pub bytes_per_page: u8,
+}
+
+impl ControlTlv {
+    fn parse(value: &[u8]) -> Option<Self> {
+        let [position, size, page_control]: [u8; 3] = value.try_into().ok()?;
+        Some(Self {
+            page_address: position >> 4,
+            byte_offset: position & 0x0f,
+            size: match size {
+                0 => 256,
+                size => u16::from(size),
+            },
+            bytes_locked_per_bit: page_control >> 4,
+            bytes_per_page: page_control & 0x0f,
+        })
+    }
+
+    /// Absolute byte address of the area this TLV describes.
+    pub fn byte_address(&self) -> usize {
+        usize::from(self.page_address) * (1 << self.bytes_per_page) + usize::from(self.byte_offset)
     }
 }
 
 pub struct MessageHeader {
     pub init: u8,
-    pub len: u8,
+    pub len: u16,
+    /// Offset of the NDEF message from the start of the parsed data
+    pub offset: usize,
+    pub lock_controls: Vec<ControlTlv>,
+    pub memory_controls: Vec<ControlTlv>,
+}
+
+impl MessageHeader {
+    /// Number of bytes from the start of the parsed data up to the end of the message.
+    pub fn end(&self) -> usize {
+        self.offset + usize::from(self.len)
+    }
 }
 
 pub struct RecordHeader<'a> {
@@ -137,8 +229,82 @@ pub struct RecordRaw<'a> {
     pub payload: &'a [u8],
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmartPosterAction {
+    Do,
+    Save,
+    Edit,
+}
+
+impl SmartPosterAction {
+    fn from_byte(action: u8) -> Option<Self> {
+        match action {
+            0x00 => Some(SmartPosterAction::Do),
+            0x01 => Some(SmartPosterAction::Save),
+            0x02 => Some(SmartPosterAction::Edit),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            SmartPosterAction::Do => 0x00,
+            SmartPosterAction::Save => 0x01,
+            SmartPosterAction::Edit => 0x02,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Record {
-    URI { uri: String },
+    URI {
+        uri: String,
+    },
+    Text {
+        language: String,
+        text: String,
+    },
+    /// Titles are kept as (language, text) pairs in the order they appear.
+    SmartPoster {
+        uri: String,
+        titles: Vec<(String, String)>,
+        action: Option<SmartPosterAction>,
+        size: Option<u32>,
+    },
+    Mime {
+        mime_type: String,
+        payload: Vec<u8>,
+    },
+    /// NFC Forum external type, e.g. `example.com:foo`
+    External {
+        record_type: String,
+        payload: Vec<u8>,
+    },
+    /// Any record we don't decode (yet), kept so callers can skip over it.
+    Unknown {
+        tnf: u8,
+        record_type: Vec<u8>,
+        payload: Vec<u8>,
+    },
+}
+
+impl Record {
+    pub fn uri(&self) -> Option<&str> {
+        match self {
+            Record::URI { uri } | Record::SmartPoster { uri, .. } => Some(uri.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        match self {
+            Record::Text { text, .. } => Some(text.as_str()),
+            Record::SmartPoster { titles, .. } => titles.first().map(|(_, text)| text.as_str()),
+            _ => None,
+        }
+    }
 }
 
 pub struct Message {
@@ -147,30 +313,76 @@ pub struct Message {
 }
 
 impl Message {
-    // TODO: This is a slightly less terrible ndef "parser" which is barely MVP ready!
-    const MESSAGE_INIT_MARKER: u8 = 0x03;
+    const TEXT_STATUS_UTF16: u8 = 0b10000000;
+    const TEXT_STATUS_LANGUAGE_LEN_MASK: u8 = 0b00111111;
 
-    fn parse_message_header(bg: &mut ByteGetter) -> Option<MessageHeader> {
-        let message_init = bg.get_byte()?;
-        debug!(message_init);
-        if message_init != Self::MESSAGE_INIT_MARKER {
-            error!("NDEF Message init marker not found!");
-            return None;
+    fn parse_tlv_length(bg: &mut ByteGetter) -> Option<u16> {
+        match bg.get_byte()? {
+            tlv::LONG_LENGTH_MARKER => Some(u16::from_be_bytes(bg.get_bytes_const::<2>()?)),
+            len => Some(u16::from(len)),
         }
+    }
 
-        let message_len = bg.get_byte()?;
-        debug!(message_len);
-        bg.set_len(message_len as usize);
+    /// Walks the TLV blocks up to the first NDEF Message TLV and limits `bg` to its value.
+    fn parse_message_header(bg: &mut ByteGetter) -> Option<MessageHeader> {
+        let mut lock_controls = Vec::new();
+        let mut memory_controls = Vec::new();
 
-        let message_header = MessageHeader {
-            init: message_init,
-            len: message_len,
-        };
+        loop {
+            let tlv_type = bg.get_byte()?;
+            debug!(tlv_type);
+
+            match tlv_type {
+                tlv::NULL => continue,
+                tlv::TERMINATOR => {
+                    error!("Reached terminator TLV before any NDEF message!");
+                    return None;
+                }
+                _ => {}
+            }
+
+            let tlv_len = Message::parse_tlv_length(bg)?;
+            debug!(tlv_len);
+
+            match tlv_type {
+                tlv::NDEF_MESSAGE => {
+                    bg.set_len(usize::from(tlv_len));
+
+                    return Some(MessageHeader {
+                        init: tlv_type,
+                        len: tlv_len,
+                        offset: bg.position(),
+                        lock_controls,
+                        memory_controls,
+                    });
+                }
+                tlv::LOCK_CONTROL => {
+                    let value = bg.get_bytes(usize::from(tlv_len))?;
+                    lock_controls.push(ControlTlv::parse(value)?);
+                }
+                tlv::MEMORY_CONTROL => {
+                    let value = bg.get_bytes(usize::from(tlv_len))?;
+                    memory_controls.push(ControlTlv::parse(value)?);
+                }
+                tlv::PROPRIETARY => {
+                    bg.get_bytes(usize::from(tlv_len))?;
+                }
+                _ => {
+                    // reserved TLV types still follow the TLV format, so we can skip them
+                    debug!(tlv_type, "skipping unknown TLV");
+                    bg.get_bytes(usize::from(tlv_len))?;
+                }
+            }
+        }
+    }
 
-        Some(message_header)
+    /// Parses only the TLV structure, which tells how many bytes the full message needs.
+    pub fn parse_header(data: &[u8]) -> Option<MessageHeader> {
+        let mut bg = ByteGetter::new(data);
+        Message::parse_message_header(&mut bg)
     }
 
-    fn parse_record_raw<'a>(bg: &'a mut ByteGetter<'a>) -> Option<RecordRaw<'a>> {
+    fn parse_record_raw<'a>(bg: &mut ByteGetter<'a>) -> Option<RecordRaw<'a>> {
         let flags_tnf = Flags::from_bits(bg.get_byte()?)?;
         let type_length = bg.get_byte()? as usize;
 
@@ -212,43 +424,411 @@ impl Message {
         Some(record_raw)
     }
 
-    fn parse_uri_record(record_raw: RecordRaw) -> Option<Record> {
-        let prefix = PREFIX_STRINGS[usize::from(record_raw.payload[0])];
-        let payload = str::from_utf8(&record_raw.payload[1..]).ok()?;
+    fn parse_uri_record(record_raw: &RecordRaw) -> Option<Record> {
+        let (prefix_code, payload) = record_raw.payload.split_first()?;
+        let prefix = match PREFIX_STRINGS.get(usize::from(*prefix_code)) {
+            Some(prefix) => prefix,
+            None => {
+                error!(prefix_code, "Unknown URI prefix code!");
+                return None;
+            }
+        };
+        let payload = str::from_utf8(payload).ok()?;
         let uri = [prefix, payload].join("");
         Some(Record::URI { uri })
     }
 
-    fn parse_records<'a>(bg: &'a mut ByteGetter<'a>) -> Option<Vec<Record>> {
+    fn parse_text_record(record_raw: &RecordRaw) -> Option<Record> {
+        let mut bg = ByteGetter::new(record_raw.payload);
+
+        let status = bg.get_byte()?;
+        let is_utf16 = status & Self::TEXT_STATUS_UTF16 != 0;
+        let language_len = usize::from(status & Self::TEXT_STATUS_LANGUAGE_LEN_MASK);
+
+        let language = str::from_utf8(bg.get_bytes(language_len)?).ok()?;
+        let text = bg.get_bytes(record_raw.payload.len() - 1 - language_len)?;
+
+        let text = match is_utf16 {
+            true => Message::decode_utf16(text)?,
+            false => String::from(str::from_utf8(text).ok()?),
+        };
+
+        Some(Record::Text {
+            language: String::from(language),
+            text,
+        })
+    }
+
+    fn decode_utf16(data: &[u8]) -> Option<String> {
+        if !data.len().is_multiple_of(2) {
+            error!("UTF-16 text has odd byte count!");
+            return None;
+        }
+
+        // big endian unless a byte order mark says otherwise
+        let (data, little_endian) = match data {
+            [0xfe, 0xff, rest @ ..] => (rest, false),
+            [0xff, 0xfe, rest @ ..] => (rest, true),
+            _ => (data, false),
+        };
+
+        let units = data.chunks_exact(2).map(|unit| match little_endian {
+            true => u16::from_le_bytes([unit[0], unit[1]]),
+            false => u16::from_be_bytes([unit[0], unit[1]]),
+        });
+
+        char::decode_utf16(units)
+            .collect::<Result<String, _>>()
+            .ok()
+    }
+
+    fn parse_smart_poster_record(record_raw: &RecordRaw) -> Option<Record> {
+        // the payload of a smart poster is a complete NDEF message of its own
+        let mut bg = ByteGetter::new(record_raw.payload);
+        let records = Message::parse_records(&mut bg)?;
+
+        let mut uri = None;
+        let mut titles = Vec::new();
+        let mut action = None;
+        let mut size = None;
+
+        for record in records {
+            match record {
+                Record::URI { uri: record_uri } => {
+                    if uri.is_none() {
+                        uri = Some(record_uri);
+                    }
+                }
+                Record::Text { language, text } => titles.push((language, text)),
+                Record::Unknown {
+                    tnf,
+                    record_type,
+                    payload,
+                } if tnf == Flags::TNF_NFC_WELL_KNOWN.bits() => {
+                    match WellKnownType::from_type(&record_type) {
+                        Some(WellKnownType::Action) => {
+                            action = payload
+                                .first()
+                                .copied()
+                                .and_then(SmartPosterAction::from_byte)
+                        }
+                        Some(WellKnownType::Size) => {
+                            size = payload.as_slice().try_into().ok().map(u32::from_be_bytes)
+                        }
+                        _ => debug!("ignoring smart poster record {:02x?}", record_type),
+                    }
+                }
+                _ => debug!("ignoring smart poster record {:?}", record),
+            }
+        }
+
+        let uri = match uri {
+            Some(uri) => uri,
+            None => {
+                error!("Smart poster without URI record!");
+                return None;
+            }
+        };
+
+        Some(Record::SmartPoster {
+            uri,
+            titles,
+            action,
+            size,
+        })
+    }
+
+    fn parse_record(record_raw: &RecordRaw) -> Option<Record> {
+        let tnf = record_raw.header.flags_tnf.tnf();
+        let record_type = record_raw.header.payload_type.unwrap_or_default();
+
+        if tnf == Flags::TNF_NFC_WELL_KNOWN {
+            match WellKnownType::from_type(record_type) {
+                Some(WellKnownType::URI) => return Message::parse_uri_record(record_raw),
+                Some(WellKnownType::Text) => return Message::parse_text_record(record_raw),
+                Some(WellKnownType::SmartPoster) => {
+                    return Message::parse_smart_poster_record(record_raw)
+                }
+                // action and size records only carry meaning inside a smart poster
+                Some(WellKnownType::Action) | Some(WellKnownType::Size) | None => {}
+            }
+        }
+
+        if let Ok(type_name) = str::from_utf8(record_type) {
+            if tnf == Flags::TNF_MEDIA {
+                return Some(Record::Mime {
+                    mime_type: String::from(type_name),
+                    payload: record_raw.payload.to_vec(),
+                });
+            }
+            if tnf == Flags::TNF_NFC_EXTERNAL {
+                return Some(Record::External {
+                    record_type: String::from(type_name),
+                    payload: record_raw.payload.to_vec(),
+                });
+            }
+        }
+
+        Some(Record::Unknown {
+            tnf: tnf.bits(),
+            record_type: record_type.to_vec(),
+            payload: record_raw.payload.to_vec(),
+        })
+    }
+
+    fn parse_records(bg: &mut ByteGetter) -> Option<Vec<Record>> {
         let mut records = Vec::<Record>::new();
+        let mut first_record = true;
 
-        // loop {
-        let record_raw = Message::parse_record_raw(bg)?;
-        // let last_record = record_raw.header.flags_tnf.contains(Flags::MESSAGE_END);
+        loop {
+            let record_raw = Message::parse_record_raw(bg)?;
+            let flags = record_raw.header.flags_tnf;
 
-        // TODO: this is just parsing URI records right now!
-        let uri_record = Message::parse_uri_record(record_raw)?;
-        records.push(uri_record);
+            if first_record != flags.contains(Flags::MESSAGE_BEGIN) {
+                error!("NDEF record has unexpected MESSAGE_BEGIN flag!");
+                return None;
+            }
 
-        //     if last_record {
-        //         break;
-        //     }
-        // }
+            if flags.contains(Flags::CHUNK) {
+                // chunked records are not supported, skip them
+                error!("Skipping chunked NDEF record!");
+            } else {
+                match Message::parse_record(&record_raw) {
+                    Some(record) => records.push(record),
+                    None => error!("Skipping malformed NDEF record!"),
+                }
+            }
+            first_record = false;
+
+            if flags.contains(Flags::MESSAGE_END) {
+                break;
+            }
+        }
 
         Some(records)
     }
 
+    /// The first title found in a Text or Smart Poster record.
+    pub fn title(&self) -> Option<&str> {
+        self.records.iter().find_map(Record::title)
+    }
+
     pub fn parse(data: &[u8]) -> Option<Message> {
         let mut bg = ByteGetter::new(data);
 
         let message_header = Message::parse_message_header(&mut bg)?;
-        let records = Message::parse_records(&mut bg)?;
+
+        // an empty NDEF message TLV is how blank tags are formatted
+        let records = match message_header.len {
+            0 => Vec::new(),
+            _ => Message::parse_records(&mut bg)?,
+        };
+
+        Some(Self {
+            message_header,
+            records,
+        })
+    }
+}
+
+impl Message {
+    /// Largest NDEF message that fits the 3-byte TLV length format
+    const MAX_MESSAGE_LEN: usize = 0xfffe;
+    const SHORT_RECORD_MAX_PAYLOAD_LEN: usize = u8::MAX as usize;
+    const MAX_RECORD_TYPE_LEN: usize = u8::MAX as usize;
+
+    /// Builds a message from records, failing if it would be too large for a tag or a record
+    /// type or text language doesn't fit its length field.
+    pub fn new(records: Vec<Record>) -> Option<Message> {
+        if let Some(record) = records.iter().find(|record| !Self::lengths_fit(record)) {
+            error!(?record, "NDEF record type or language too long!");
+            return None;
+        }
+        let len = Message::encode_records(&records).len();
+        if len > Self::MAX_MESSAGE_LEN {
+            error!(len, "NDEF message too large!");
+            return None;
+        }
+        let len = len as u16;
+
+        let message_header = MessageHeader {
+            init: tlv::NDEF_MESSAGE,
+            len,
+            offset: match u8::try_from(len) {
+                Ok(tlv::LONG_LENGTH_MARKER) | Err(_) => 4,
+                Ok(_) => 2,
+            },
+            lock_controls: Vec::new(),
+            memory_controls: Vec::new(),
+        };
 
         Some(Self {
             message_header,
             records,
         })
     }
+
+    /// The raw NDEF message, without any TLV wrapping.
+    pub fn encode(&self) -> Vec<u8> {
+        Message::encode_records(&self.records)
+    }
+
+    /// The message wrapped in an NDEF Message TLV followed by a Terminator TLV,
+    /// ready to be written to the user memory of a Type 2 tag.
+    pub fn to_tlv(&self) -> Vec<u8> {
+        let message = self.encode();
+        let mut data = Vec::with_capacity(message.len() + 5);
+
+        data.push(tlv::NDEF_MESSAGE);
+        match u8::try_from(message.len()) {
+            Ok(len) if len != tlv::LONG_LENGTH_MARKER => data.push(len),
+            _ => {
+                data.push(tlv::LONG_LENGTH_MARKER);
+                data.extend((message.len() as u16).to_be_bytes());
+            }
+        }
+        data.extend(message);
+        data.push(tlv::TERMINATOR);
+
+        data
+    }
+
+    fn lengths_fit(record: &Record) -> bool {
+        let language_fits =
+            |language: &str| language.len() <= usize::from(Self::TEXT_STATUS_LANGUAGE_LEN_MASK);
+        match record {
+            Record::URI { .. } => true,
+            Record::Text { language, .. } => language_fits(language),
+            Record::SmartPoster { titles, .. } => {
+                titles.iter().all(|(language, _)| language_fits(language))
+            }
+            Record::Mime {
+                mime_type: record_type,
+                ..
+            }
+            | Record::External { record_type, .. } => {
+                record_type.len() <= Self::MAX_RECORD_TYPE_LEN
+            }
+            Record::Unknown { record_type, .. } => record_type.len() <= Self::MAX_RECORD_TYPE_LEN,
+        }
+    }
+
+    fn encode_records(records: &[Record]) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for (index, record) in records.iter().enumerate() {
+            let (tnf, record_type, payload) = Message::encode_record(record);
+
+            let mut flags = tnf;
+            flags.set(Flags::MESSAGE_BEGIN, index == 0);
+            flags.set(Flags::MESSAGE_END, index == records.len() - 1);
+            flags.set(
+                Flags::SHORT_RECORD,
+                payload.len() <= Self::SHORT_RECORD_MAX_PAYLOAD_LEN,
+            );
+
+            data.push(flags.bits());
+            data.push(record_type.len() as u8);
+            match flags.contains(Flags::SHORT_RECORD) {
+                true => data.push(payload.len() as u8),
+                false => data.extend((payload.len() as u32).to_be_bytes()),
+            }
+            data.extend(record_type);
+            data.extend(payload);
+        }
+
+        data
+    }
+
+    fn encode_record(record: &Record) -> (Flags, Vec<u8>, Vec<u8>) {
+        match record {
+            Record::URI { uri } => (
+                Flags::TNF_NFC_WELL_KNOWN,
+                b"U".to_vec(),
+                Message::encode_uri(uri),
+            ),
+            Record::Text { language, text } => (
+                Flags::TNF_NFC_WELL_KNOWN,
+                b"T".to_vec(),
+                Message::encode_text(language, text),
+            ),
+            Record::SmartPoster {
+                uri,
+                titles,
+                action,
+                size,
+            } => {
+                let mut records = vec![Record::URI { uri: uri.clone() }];
+                records.extend(titles.iter().map(|(language, text)| Record::Text {
+                    language: language.clone(),
+                    text: text.clone(),
+                }));
+                if let Some(action) = action {
+                    records.push(Record::Unknown {
+                        tnf: Flags::TNF_NFC_WELL_KNOWN.bits(),
+                        record_type: b"act".to_vec(),
+                        payload: vec![action.to_byte()],
+                    });
+                }
+                if let Some(size) = size {
+                    records.push(Record::Unknown {
+                        tnf: Flags::TNF_NFC_WELL_KNOWN.bits(),
+                        record_type: b"s".to_vec(),
+                        payload: size.to_be_bytes().to_vec(),
+                    });
+                }
+                (
+                    Flags::TNF_NFC_WELL_KNOWN,
+                    b"Sp".to_vec(),
+                    Message::encode_records(&records),
+                )
+            }
+            Record::Mime { mime_type, payload } => (
+                Flags::TNF_MEDIA,
+                mime_type.as_bytes().to_vec(),
+                payload.clone(),
+            ),
+            Record::External {
+                record_type,
+                payload,
+            } => (
+                Flags::TNF_NFC_EXTERNAL,
+                record_type.as_bytes().to_vec(),
+                payload.clone(),
+            ),
+            Record::Unknown {
+                tnf,
+                record_type,
+                payload,
+            } => (
+                Flags::from_bits_truncate(*tnf).tnf(),
+                record_type.clone(),
+                payload.clone(),
+            ),
+        }
+    }
+
+    /// Abbreviates the URI with the longest matching entry of the prefix table.
+    fn encode_uri(uri: &str) -> Vec<u8> {
+        let (prefix_code, prefix) = PREFIX_STRINGS
+            .iter()
+            .enumerate()
+            .filter(|(_, prefix)| uri.starts_with(*prefix))
+            .max_by_key(|(_, prefix)| prefix.len())
+            .unwrap_or((0, &""));
+
+        let mut payload = vec![prefix_code as u8];
+        payload.extend(&uri.as_bytes()[prefix.len()..]);
+        payload
+    }
+
+    fn encode_text(language: &str, text: &str) -> Vec<u8> {
+        // we always encode as UTF-8, so the UTF-16 flag stays clear
+        let mut payload = vec![language.len() as u8];
+        payload.extend(language.as_bytes());
+        payload.extend(text.as_bytes());
+        payload
+    }
 }
 
 #[cfg(test)]
@@ -281,6 +861,239 @@ mod tests {
             Record::URI { uri } => {
                 assert_eq!(uri, URI_DECODED);
             }
+            _ => panic!("expected URI record"),
         }
+
+        let encoded = Message::new(message.records).unwrap().to_tlv();
+        assert_eq!(encoded, NDEF_MESSAGE);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let records = vec![
+            Record::SmartPoster {
+                uri: String::from("https://www.example.com/"),
+                titles: vec![(String::from("en"), String::from("Example"))],
+                action: Some(SmartPosterAction::Do),
+                size: Some(1234),
+            },
+            Record::Text {
+                language: String::from("de"),
+                text: String::from("Hörspiel"),
+            },
+            Record::Mime {
+                mime_type: String::from("text/plain"),
+                payload: vec![0x61; 300],
+            },
+            Record::External {
+                record_type: String::from("example.com:foo"),
+                payload: vec![0x01, 0x02],
+            },
+            Record::URI {
+                uri: String::from("spotify:track:4abJbqX8C8CQTXHZxEbJZz"),
+            },
+        ];
+
+        let message = Message::new(records.clone()).unwrap();
+        let encoded = message.to_tlv();
+        let decoded = Message::parse(&encoded).unwrap();
+
+        assert_eq!(&encoded[..4], [0x03, 0xff, 0x01, 0xbc]);
+        assert_eq!(message.message_header.end(), encoded.len() - 1);
+        assert_eq!(decoded.records, records);
+    }
+
+    #[test]
+    fn parse_multiple_records() {
+        const NDEF_MESSAGE: [u8; 28] = [
+            // header
+            0x03, 0x18, //
+            // record 1: MB, SR, TNF_MEDIA "a/b", payload "xy"
+            0x92, 0x03, 0x02, 0x61, 0x2f, 0x62, 0x78, 0x79, //
+            // record 2: SR, TNF_NFC_WELL_KNOWN "U", "https://" + "a.bc"
+            0x11, 0x01, 0x05, 0x55, 0x04, 0x61, 0x2e, 0x62, 0x63, //
+            // record 3: ME, SR, TNF_NFC_WELL_KNOWN "U", "tel:" + "12"
+            0x51, 0x01, 0x03, 0x55, 0x05, 0x31, 0x32, //
+            0xfe, 0x00,
+        ];
+
+        let message = Message::parse(&NDEF_MESSAGE).unwrap();
+
+        assert_eq!(
+            message.records,
+            vec![
+                Record::Mime {
+                    mime_type: String::from("a/b"),
+                    payload: b"xy".to_vec(),
+                },
+                Record::URI {
+                    uri: String::from("https://a.bc"),
+                },
+                Record::URI {
+                    uri: String::from("tel:12"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_missing_message_begin() {
+        const NDEF_MESSAGE: [u8; 9] = [
+            0x03, 0x07, 0x51, 0x01, 0x03, 0x55, 0x05, 0x31, 0x32, //
+        ];
+
+        assert!(Message::parse(&NDEF_MESSAGE).is_none());
+    }
+
+    #[test]
+    fn parse_text() {
+        const NDEF_MESSAGE: [u8; 15] = [
+            // header
+            0x03, 0x0b, //
+            // MB, ME, SR, TNF_NFC_WELL_KNOWN "T", status: UTF-8, language length 2
+            0xd1, 0x01, 0x07, 0x54, 0x02, //
+            // "de" "Maus"
+            0x64, 0x65, 0x4d, 0x61, 0x75, 0x73, //
+            0xfe, 0x00,
+        ];
+
+        let message = Message::parse(&NDEF_MESSAGE).unwrap();
+
+        assert_eq!(
+            message.records[0],
+            Record::Text {
+                language: String::from("de"),
+                text: String::from("Maus"),
+            }
+        );
+        assert_eq!(message.title(), Some("Maus"));
+    }
+
+    #[test]
+    fn parse_text_utf16() {
+        const NDEF_MESSAGE: [u8; 18] = [
+            // header
+            0x03, 0x0f, //
+            // MB, ME, SR, TNF_NFC_WELL_KNOWN "T", status: UTF-16, language length 2
+            0xd1, 0x01, 0x0b, 0x54, 0x82, //
+            // "en", little endian BOM, "Bär"
+            0x65, 0x6e, 0xff, 0xfe, 0x42, 0x00, 0xe4, 0x00, 0x72, 0x00, //
+            0xfe,
+        ];
+
+        let message = Message::parse(&NDEF_MESSAGE).unwrap();
+
+        assert_eq!(
+            message.records[0],
+            Record::Text {
+                language: String::from("en"),
+                text: String::from("Bär"),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_smart_poster() {
+        const NDEF_MESSAGE: [u8; 37] = [
+            // header
+            0x03, 0x1e, //
+            // MB, ME, SR, TNF_NFC_WELL_KNOWN "Sp"
+            0xd1, 0x02, 0x19, 0x53, 0x70, //
+            // nested: MB, SR, TNF_NFC_WELL_KNOWN "U", "https://" + "a.bc"
+            0x91, 0x01, 0x05, 0x55, 0x04, 0x61, 0x2e, 0x62, 0x63, //
+            // nested: SR, TNF_NFC_WELL_KNOWN "T", UTF-8, "en" "Hi"
+            0x11, 0x01, 0x05, 0x54, 0x02, 0x65, 0x6e, 0x48, 0x69, //
+            // nested: ME, SR, TNF_NFC_WELL_KNOWN "act", save
+            0x51, 0x03, 0x01, 0x61, 0x63, 0x74, 0x01, //
+            0xfe, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let message = Message::parse(&NDEF_MESSAGE).unwrap();
+
+        assert_eq!(
+            message.records[0],
+            Record::SmartPoster {
+                uri: String::from("https://a.bc"),
+                titles: vec![(String::from("en"), String::from("Hi"))],
+                action: Some(SmartPosterAction::Save),
+                size: None,
+            }
+        );
+        assert_eq!(message.records[0].uri(), Some("https://a.bc"));
+        assert_eq!(message.title(), Some("Hi"));
+    }
+
+    #[test]
+    fn parse_tlvs() {
+        let mut data = vec![
+            // NULL TLVs
+            0x00, 0x00, //
+            // Lock Control TLV: page 0x0a, offset 0, 0x50 bits, 16 bytes per bit, 4 byte pages
+            0x01, 0x03, 0xa0, 0x50, 0x42, //
+            // Memory Control TLV
+            0x02, 0x03, 0xb0, 0x20, 0x02, //
+            // Proprietary TLV
+            0xfd, 0x02, 0x12, 0x34, //
+            // NDEF Message TLV with 3-byte length
+            0x03, 0xff, 0x01, 0x05, //
+            // MB, ME, TNF_NFC_WELL_KNOWN "U", 4-byte payload length, "https://"
+            0xc1, 0x01, 0x00, 0x00, 0x00, 0xfe, 0x55, 0x04, //
+        ];
+        let path = "a".repeat(0xfd);
+        data.extend(path.as_bytes());
+        data.push(0xfe);
+
+        let message = Message::parse(&data).unwrap();
+
+        assert_eq!(message.message_header.len, 0x105);
+        assert_eq!(message.message_header.offset, 20);
+        assert_eq!(message.message_header.end(), data.len() - 1);
+        assert_eq!(
+            message.message_header.lock_controls,
+            vec![ControlTlv {
+                page_address: 0x0a,
+                byte_offset: 0,
+                size: 0x50,
+                bytes_locked_per_bit: 4,
+                bytes_per_page: 2,
+            }]
+        );
+        assert_eq!(message.message_header.lock_controls[0].byte_address(), 40);
+        assert_eq!(message.message_header.memory_controls.len(), 1);
+        assert_eq!(
+            message.records[0].uri(),
+            Some(["https://", &path].join("").as_str())
+        );
+    }
+
+    #[test]
+    fn reject_oversized_lengths() {
+        assert!(Message::new(vec![Record::External {
+            record_type: "a".repeat(256),
+            payload: Vec::new(),
+        }])
+        .is_none());
+        assert!(Message::new(vec![Record::Text {
+            language: "a".repeat(64),
+            text: String::from("Maus"),
+        }])
+        .is_none());
+
+        let message = Message::new(vec![Record::External {
+            record_type: "a".repeat(255),
+            payload: Vec::new(),
+        }])
+        .unwrap();
+        assert_eq!(
+            Message::parse(&message.to_tlv()).unwrap().records,
+            message.records
+        );
+    }
+
+    #[test]
+    fn reject_terminator_before_message() {
+        const DATA: [u8; 4] = [0x00, 0xfe, 0x03, 0x00];
+
+        assert!(Message::parse(&DATA).is_none());
     }
 }
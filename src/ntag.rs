@@ -1,132 +1,674 @@
-use crate::ndef::Record;
-use crate::ntag215::NTAG215;
+use crate::action::{self, Action};
+use crate::mfrc522::Mfrc522;
+use crate::ndef::Message;
+use crate::ntag21x::{
+    NTAG21x, Originality, Password, PasswordConfig, ReadError, TagDump, TagInfo, WriteError,
+    WriteSummary,
+};
+use crate::playback_options::PlaybackOptions;
 use crate::player::PlayerRequestMessage;
+use crate::pn532::{Interface, Pn532};
+use crate::presence::{Presence, PresenceEvent};
+use crate::reader::{Reader, ReaderHealth, ReaderState, Uid};
 use crate::server::AppState;
-use crate::tuple_windows::TupleWindowsExt;
+use crate::tag_events::TagEvent;
 use async_std::sync::Arc;
-use mfrc522::comm::eh02::spi::SpiInterface;
-use mfrc522::Mfrc522;
-use rppal::gpio::Gpio;
+use rppal::gpio::{Gpio, InputPin, Level, Trigger};
+use rppal::i2c::I2c;
 use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
-use tokio::sync::Mutex;
+use std::env;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use tokio::task::JoinSet;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
-pub async fn start_ntag_reader_task(join_set: &mut JoinSet<()>, app_state: AppState) {
-    match start_ntag_reader_task_impl(join_set, app_state).await {
-        Ok(_) => {}
-        Err(e) => error!(e, "error starting ntag reader task"),
-    }
+const IRQ_GPIO_PIN: u8 = 24;
+const RESET_GPIO_PIN: u8 = 25;
+
+/// Reader faults in a row before the reader gets reset
+const MAX_READER_ERRORS: usize = 5;
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const RECOVERY_BACKOFF: Duration = Duration::from_secs(5);
+/// How long to wait before trying to bring up a missing reader again
+const INIT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const CONFIRMATION_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the REQA for the card detect IRQ gets sent while no tag is present.
+const CARD_DETECT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub enum TagRequestMessage {
+    Write {
+        message: Message,
+        password: Option<Password>,
+        responder: oneshot::Sender<Result<WriteSummary, WriteError>>,
+    },
+    SetPassword {
+        config: PasswordConfig,
+        /// Needed when the tag is password protected already
+        current: Option<Password>,
+        responder: oneshot::Sender<Result<Uid, WriteError>>,
+    },
+    RemovePassword {
+        password: Password,
+        responder: oneshot::Sender<Result<Uid, WriteError>>,
+    },
+    Dump {
+        responder: oneshot::Sender<Result<TagDump, ReadError>>,
+    },
+    /// Makes the tag permanently read-only
+    Lock {
+        password: Option<Password>,
+        responder: oneshot::Sender<Result<Uid, WriteError>>,
+    },
 }
 
-fn delay() {
-    std::thread::sleep(Duration::from_nanos(50));
+#[derive(Clone, Copy)]
+enum Backend {
+    Mfrc522,
+    Pn532Spi,
+    Pn532I2c,
 }
 
-async fn start_ntag_reader_task_impl(
+/// Starts the reader in the background. Without a working reader the rest of the box keeps
+/// running, we just keep trying to bring it up.
+pub async fn start_ntag_reader_task(
     join_set: &mut JoinSet<()>,
     app_state: AppState,
-) -> Result<(), Box<dyn std::error::Error>> {
+    tag_receiver: mpsc::Receiver<TagRequestMessage>,
+    health: watch::Sender<ReaderHealth>,
+    tag_info: watch::Sender<Option<TagInfo>>,
+) {
+    let reader = env::var("NFC_READER").unwrap_or(String::from("mfrc522"));
+    let backend = match reader.as_str() {
+        "mfrc522" => Backend::Mfrc522,
+        "pn532-spi" => Backend::Pn532Spi,
+        "pn532-i2c" => Backend::Pn532I2c,
+        _ => {
+            let e =
+                format!("unknown NFC_READER {reader}, expected mfrc522, pn532-spi or pn532-i2c");
+            error!(e, "error starting ntag reader task");
+            health.send_modify(|health| {
+                health.state = ReaderState::Failed;
+                health.last_error = Some(e);
+            });
+            return;
+        }
+    };
+
     info!("Starting NTAG reader...");
+    join_set.spawn(async move {
+        let mut tasks = JoinSet::new();
+        match backend {
+            Backend::Mfrc522 => {
+                let (mfrc522, card_detect) = retry_init(&health, start_mfrc522).await;
+                spawn_reader_tasks(
+                    &mut tasks,
+                    app_state,
+                    tag_receiver,
+                    health,
+                    tag_info,
+                    mfrc522,
+                    card_detect,
+                );
+            }
+            Backend::Pn532Spi | Backend::Pn532I2c => {
+                let pn532 = retry_init(&health, || start_pn532(backend)).await;
+                spawn_reader_tasks(
+                    &mut tasks,
+                    app_state,
+                    tag_receiver,
+                    health,
+                    tag_info,
+                    pn532,
+                    None,
+                );
+            }
+        }
+
+        // the reader tasks don't stop by themselves, so pass on the first one that does
+        if let Some(Err(e)) = tasks.join_next().await {
+            let e = e.to_string();
+            error!(e, "reader task failed");
+        }
+    });
+}
+
+/// Keeps calling `init` until the reader comes up, reporting it as missing in the meantime.
+async fn retry_init<T, F, Fut>(health: &watch::Sender<ReaderHealth>, mut init: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    loop {
+        let e = match init().await {
+            Ok(reader) => {
+                health.send_modify(|health| health.state = ReaderState::Ok);
+                return reader;
+            }
+            Err(e) => e.to_string(),
+        };
+        warn!(e, "reader not available, retrying");
+        health.send_modify(|health| {
+            health.state = ReaderState::Missing;
+            health.last_error = Some(e);
+        });
+        sleep(INIT_RETRY_INTERVAL).await;
+    }
+}
+
+/// The first URI on the tag, or action record as a `drempelbox:` URI.
+fn first_playable_url(message: &Message) -> Option<Url> {
+    message
+        .records
+        .iter()
+        .find_map(|record| match record.uri() {
+            Some(uri) => match Url::parse(uri) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    let e = e.to_string();
+                    error!(e, uri, "error parsing url from token");
+                    None
+                }
+            },
+            None => Action::url_from_record(record),
+        })
+}
+
+/// Card detection through the MFRC522 IRQ pin. The chip can't look for cards by itself, but
+/// arming a REQA only takes a few SPI writes, and we only need to talk to it again once a
+/// card answered, instead of busy waiting for each answer.
+struct CardDetect {
+    _pin: InputPin,
+    events: mpsc::UnboundedReceiver<()>,
+}
+
+impl CardDetect {
+    /// Returns `None` if the IRQ pin doesn't follow the chip, e.g. because it isn't wired up.
+    fn new(gpio: &Gpio, mfrc522: &mut Mfrc522) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let mut pin = gpio.get(IRQ_GPIO_PIN)?.into_input_pullup();
+
+        mfrc522.assert_test_irq()?;
+        let asserted = pin.read() == Level::Low;
+        mfrc522.disable_irq()?;
+        let released = pin.read() == Level::High;
+        if !(asserted && released) {
+            return Ok(None);
+        }
+
+        let (sender, events) = mpsc::unbounded_channel();
+        pin.set_async_interrupt(Trigger::FallingEdge, None, move |_| {
+            // the receiving end only goes away with the poll task
+            let _ = sender.send(());
+        })?;
+        mfrc522.enable_card_detect_irq()?;
+
+        Ok(Some(Self { _pin: pin, events }))
+    }
 
+    /// Keeps arming the card detect REQA until a card answers or `duration` has passed.
+    async fn wait<R: Reader>(&mut self, ntag: &Mutex<NTAG21x<R>>, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            let armed = {
+                let mut ntag = ntag.lock().await;
+                // drop edges caused by regular reads and writes
+                while self.events.try_recv().is_ok() {}
+                ntag.reader.arm_card_detect()
+            };
+            if let Err(e) = armed {
+                error!(%e, "error arming card detect");
+                sleep_until(deadline).await;
+                return;
+            }
+
+            if timeout(CARD_DETECT_INTERVAL, self.events.recv())
+                .await
+                .is_ok()
+            {
+                debug!("card detect IRQ");
+                return;
+            }
+        }
+    }
+}
+
+/// Checks the NXP signature, counting tags that can't be checked as not genuine.
+async fn check_originality<R: Reader>(ntag: &Mutex<NTAG21x<R>>) -> Originality {
+    match ntag.lock().await.originality() {
+        Ok(originality) => originality,
+        Err(e) => {
+            error!(%e, "error checking token signature");
+            Originality::Unavailable
+        }
+    }
+}
+
+/// Resets the reader after repeated faults or a failed health check, returns whether that
+/// brought it back.
+fn recover<R: Reader>(
+    ntag: &mut NTAG21x<R>,
+    health: &watch::Sender<ReaderHealth>,
+    fault: String,
+) -> bool {
+    warn!(fault, "reader unhealthy, resetting");
+    health.send_modify(|health| {
+        health.state = ReaderState::Recovering;
+        health.consecutive_errors = ntag.reader_errors;
+        health.last_error = Some(fault);
+    });
+
+    match ntag.recover() {
+        Ok(_) => {
+            info!("reader recovered");
+            health.send_modify(|health| {
+                health.state = ReaderState::Ok;
+                health.consecutive_errors = 0;
+                health.recoveries += 1;
+            });
+            true
+        }
+        Err(e) => {
+            error!(%e, "reader recovery failed");
+            health.send_modify(|health| {
+                health.state = ReaderState::Failed;
+                health.last_error = Some(e.to_string());
+            });
+            false
+        }
+    }
+}
+
+async fn start_pn532(backend: Backend) -> Result<Pn532, Box<dyn std::error::Error>> {
+    let interface = match backend {
+        Backend::Pn532I2c => Interface::i2c(I2c::new()?)?,
+        _ => Interface::Spi(Spi::new(
+            Bus::Spi0,
+            SlaveSelect::Ss0,
+            1_000_000,
+            Mode::Mode0,
+        )?),
+    };
+    let mut pn532 = Pn532::new(interface)?;
+    let [_, version, revision, _] = pn532.firmware_version()?;
+    info!(version, revision, "PN532 firmware version");
+
+    Ok(pn532)
+}
+
+async fn start_mfrc522() -> Result<(Mfrc522, Option<CardDetect>), Box<dyn std::error::Error>> {
     let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 1_000_000, Mode::Mode0)?;
-    let itf = SpiInterface::new(spi).with_delay(delay);
 
     let gpio = Gpio::new()?;
 
-    // Not used for now, but let's initialize it correctly
-    let _irq_pin = gpio.get(24)?.into_input_pullup();
-
-    // TODO: do we need to keep pin instances around to ensure their state remains stable?
-    let mut reset_pin = gpio.get(25)?.into_output_low();
-    sleep(Duration::from_micros(50)).await;
-    reset_pin.set_high();
-    sleep(Duration::from_micros(50)).await;
+    // the driver keeps the reset pin, to recover the chip when it gets stuck
+    let reset_pin = gpio.get(RESET_GPIO_PIN)?.into_output_high();
 
-    let mfrc522 = Mfrc522::new(itf).init()?;
+    let mut mfrc522 = Mfrc522::new(spi, Some(reset_pin))?;
     sleep(Duration::from_micros(100)).await;
 
-    let ntag = Arc::new(Mutex::new(NTAG215::new(mfrc522)));
+    // an unplugged reader reads back as garbage rather than failing the SPI transfer
+    mfrc522.health_check()?;
+    let version = mfrc522.version()?;
+    info!(version, "MFRC522 version");
+
+    let card_detect = match CardDetect::new(&gpio, &mut mfrc522) {
+        Ok(Some(card_detect)) => {
+            info!("using IRQ pin for card detection");
+            Some(card_detect)
+        }
+        Ok(None) => {
+            warn!("IRQ pin not responding, polling for cards instead");
+            None
+        }
+        Err(e) => {
+            warn!(%e, "couldn't set up IRQ pin, polling for cards instead");
+            None
+        }
+    };
+
+    Ok((mfrc522, card_detect))
+}
 
-    let (tx, rx) = tokio::sync::mpsc::channel::<Option<[u8; 7]>>(16);
+fn spawn_reader_tasks<R: Reader + Send + 'static>(
+    join_set: &mut JoinSet<()>,
+    app_state: AppState,
+    mut tag_receiver: mpsc::Receiver<TagRequestMessage>,
+    health: watch::Sender<ReaderHealth>,
+    tag_info: watch::Sender<Option<TagInfo>>,
+    reader: R,
+    mut card_detect: Option<CardDetect>,
+) {
+    let ntag = Arc::new(Mutex::new(NTAG21x::new(reader)));
 
-    let stream = ReceiverStream::new(rx);
-    let mut stream = stream.tuple_windows();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Option<Vec<u8>>>(16);
+
+    let mut stream = ReceiverStream::new(rx);
+    let mut presence = Presence::from_env();
+    // lets us hand out cards that don't work with copies made on a phone
+    let require_genuine = env::var("REQUIRE_GENUINE_TAGS").is_ok_and(|value| value == "true");
 
     let ntag_rx = ntag.clone();
     join_set.spawn(async move {
-        while let Some(value) = stream.next().await {
-            match value {
-                (None, Some(uid)) => {
-                    info!("new token: {:02x?}", uid);
-                    let mut ntag = ntag_rx.lock().await;
-
-                    let result = ntag.read();
-                    match result {
-                        Some(ndef) => {
-                            // TODO: only the first record is used
-                            let Record::URI { uri } = &ndef.records[0];
-                            let url = match Url::parse(uri) {
-                                Ok(url) => url,
-                                Err(e) => {
-                                    let e = e.to_string();
-                                    error!(e, "error parsing url from token");
-                                    continue;
+        // taking an action card off shouldn't pause whatever is playing
+        let mut action_tag = None;
+        while let Some(reading) = stream.next().await {
+            for event in presence.update(reading.as_deref()) {
+                match event {
+                    PresenceEvent::Added(uid) => {
+                        info!("new token: {:02x?}", uid);
+
+                        let (message, tag_type) = {
+                            let mut ntag = ntag_rx.lock().await;
+                            let message = match ntag.read() {
+                                Err(e) if e.is_transient() => {
+                                    warn!(%e, "transient error reading token, trying again");
+                                    ntag.read()
                                 }
+                                result => result,
                             };
-                            match app_state.sender.send(PlayerRequestMessage::URL(url)).await {
+                            (message, ntag.tag_type)
+                        };
+                        let records = message.as_ref().ok().map(|ndef| ndef.records.clone());
+                        // nobody listening is fine
+                        let _ = app_state
+                            .tag_events
+                            .send(TagEvent::added(&uid, tag_type, records));
+
+                        // the signature check only holds up playback when it decides about it
+                        let originality = match require_genuine {
+                            true => Some(check_originality(&ntag_rx).await),
+                            false => None,
+                        };
+                        tag_info.send_replace(tag_type.map(|tag_type| TagInfo {
+                            uid: uid.clone(),
+                            tag_type,
+                            originality,
+                        }));
+                        if require_genuine && originality != Some(Originality::Genuine) {
+                            warn!(?originality, "ignoring token that isn't genuine");
+                            continue;
+                        }
+
+                        'play: {
+                            // action cards leave playing or paused playback alone, so they skip
+                            // the resume logic altogether
+                            let mapping = app_state.mappings.get(&uid).await;
+                            let action = match &mapping {
+                                Some(mapping) => Url::parse(&mapping.url).ok(),
+                                None => message.as_ref().ok().and_then(first_playable_url),
+                            }
+                            .filter(|url| url.scheme() == action::SCHEME);
+                            if let Some(url) = action {
+                                info!(%url, "token is an action card");
+                                action_tag = Some(uid.clone());
+                                match app_state
+                                    .sender
+                                    .send(PlayerRequestMessage::URL {
+                                        url,
+                                        title: None,
+                                        uid: None,
+                                        options: PlaybackOptions::default(),
+                                    })
+                                    .await
+                                {
+                                    Ok(_) => {}
+                                    Err(_) => error!("couldn't send action request from ntag"),
+                                }
+                                break 'play;
+                            }
+
+                            let (sender, receiver) = oneshot::channel::<bool>();
+                            match app_state
+                                .sender
+                                .send(PlayerRequestMessage::Resume {
+                                    uid: uid.clone(),
+                                    responder: sender,
+                                })
+                                .await
+                            {
                                 Ok(_) => {}
-                                Err(_) => error!("couldn't send spotify request from ntag"),
+                                Err(_) => error!("couldn't send resume request from ntag"),
                             }
+                            if let Ok(true) = receiver.await {
+                                break 'play;
+                            }
+
+                            // a mapped UID wins over the tag contents and works for blank tags
+                            if let Some(mapping) = mapping {
+                                info!(mapping.url, "token has a UID mapping");
+                                let url = match Url::parse(&mapping.url) {
+                                    Ok(url) => url,
+                                    Err(e) => {
+                                        let e = e.to_string();
+                                        error!(e, mapping.url, "error parsing url from mapping");
+                                        break 'play;
+                                    }
+                                };
+                                let title = mapping.title;
+                                match app_state
+                                    .sender
+                                    .send(PlayerRequestMessage::URL {
+                                        url,
+                                        title,
+                                        uid: Some(uid.clone()),
+                                        options: PlaybackOptions::default(),
+                                    })
+                                    .await
+                                {
+                                    Ok(_) => {}
+                                    Err(_) => error!("couldn't send spotify request from ntag"),
+                                }
+                                break 'play;
+                            }
+
+                            match message {
+                                Ok(ndef) => {
+                                    let url = match first_playable_url(&ndef) {
+                                        Some(url) => url,
+                                        None => {
+                                            error!("no playable record on token");
+                                            break 'play;
+                                        }
+                                    };
+                                    let title = ndef.title().map(String::from);
+                                    let options = PlaybackOptions::from_message(&ndef);
+                                    if let Some(title) = &title {
+                                        info!(title, "token title");
+                                    }
+                                    match app_state
+                                        .sender
+                                        .send(PlayerRequestMessage::URL {
+                                            url,
+                                            title,
+                                            uid: Some(uid.clone()),
+                                            options,
+                                        })
+                                        .await
+                                    {
+                                        Ok(_) => {}
+                                        Err(_) => error!("couldn't send spotify request from ntag"),
+                                    }
+                                }
+                                Err(e) => error!(%e, "error reading token"),
+                            };
                         }
-                        None => error!("error parsing ndef"),
+
+                        if !require_genuine {
+                            let originality = check_originality(&ntag_rx).await;
+                            tag_info.send_if_modified(|info| match info {
+                                Some(info) if info.uid == uid => {
+                                    info.originality = Some(originality);
+                                    true
+                                }
+                                _ => false,
+                            });
+                        }
+                    }
+                    PresenceEvent::Removed(uid) => {
+                        info!("token removed: {:02x?}", uid);
+                        tag_info.send_replace(None);
+                        let _ = app_state.tag_events.send(TagEvent::removed(&uid));
+                        ntag_rx.lock().await.clear();
+                        if action_tag
+                            .take_if(|action_uid| *action_uid == uid)
+                            .is_some()
+                        {
+                            continue;
+                        }
+                        match app_state
+                            .sender
+                            .send(PlayerRequestMessage::Pause { uid })
+                            .await
+                        {
+                            Ok(_) => {}
+                            Err(_) => error!("couldn't send spotify request from ntag"),
+                        };
+                    }
+                };
+            }
+        }
+        error!("ntag task ended");
+    });
+
+    let ntag_request = ntag.clone();
+    join_set.spawn(async move {
+        while let Some(request) = tag_receiver.recv().await {
+            match request {
+                TagRequestMessage::Write {
+                    message,
+                    password,
+                    responder,
+                } => {
+                    let mut ntag = ntag_request.lock().await;
+                    let result = ntag.write(&message, password);
+                    if let Err(e) = &result {
+                        let e = e.to_string();
+                        error!(e, "error writing token");
+                    }
+                    match responder.send(result) {
+                        Ok(_) => {}
+                        Err(_) => error!("error sending tag write response"),
                     };
                 }
-                (Some(uid), None) => {
-                    info!("token removed: {:02x?}", uid);
-                    match app_state.sender.send(PlayerRequestMessage::Stop).await {
+                TagRequestMessage::SetPassword {
+                    config,
+                    current,
+                    responder,
+                } => {
+                    let mut ntag = ntag_request.lock().await;
+                    let result = ntag.set_password(&config, current);
+                    if let Err(e) = &result {
+                        let e = e.to_string();
+                        error!(e, "error setting token password");
+                    }
+                    match responder.send(result) {
                         Ok(_) => {}
-                        Err(_) => error!("couldn't send spotify request from ntag"),
+                        Err(_) => error!("error sending tag password response"),
                     };
                 }
-                _ => {}
-            };
+                TagRequestMessage::RemovePassword {
+                    password,
+                    responder,
+                } => {
+                    let mut ntag = ntag_request.lock().await;
+                    let result = ntag.remove_password(password);
+                    if let Err(e) = &result {
+                        let e = e.to_string();
+                        error!(e, "error removing token password");
+                    }
+                    match responder.send(result) {
+                        Ok(_) => {}
+                        Err(_) => error!("error sending tag password response"),
+                    };
+                }
+                TagRequestMessage::Dump { responder } => {
+                    let mut ntag = ntag_request.lock().await;
+                    let result = ntag.dump();
+                    if let Err(e) = &result {
+                        let e = e.to_string();
+                        error!(e, "error dumping token");
+                    }
+                    match responder.send(result) {
+                        Ok(_) => {}
+                        Err(_) => error!("error sending tag dump response"),
+                    };
+                }
+                TagRequestMessage::Lock {
+                    password,
+                    responder,
+                } => {
+                    let mut ntag = ntag_request.lock().await;
+                    let result = ntag.lock(password);
+                    if let Err(e) = &result {
+                        let e = e.to_string();
+                        error!(e, "error locking token");
+                    }
+                    match responder.send(result) {
+                        Ok(_) => {}
+                        Err(_) => error!("error sending tag lock response"),
+                    };
+                }
+            }
         }
-        error!("ntag task ended");
+        error!("tag request channel closed");
     });
 
     let ntag_tx = ntag.clone();
     join_set.spawn(async move {
+        let mut previous = None;
+        let mut last_health_check = Instant::now();
         loop {
             // we can probably simplify this a bunch
             let mut ntag = ntag_tx.lock().await;
             let uid = ntag.is_token_present();
-            let uid = match uid {
-                Some(uid) => {
-                    let uid: [u8; 7] = match uid.as_bytes().try_into() {
-                        Ok(res) => res,
-                        Err(_) => {
-                            error!("error casting uid!");
-                            let dummy: [u8; 7] = [0; 7];
-                            dummy
-                        }
-                    };
-                    Some(uid)
-                }
-                None => None,
+
+            let mut fault = match ntag.reader_errors >= MAX_READER_ERRORS {
+                true => ntag.last_reader_error.clone(),
+                false => None,
             };
+            if fault.is_none() && last_health_check.elapsed() >= HEALTH_CHECK_INTERVAL {
+                last_health_check = Instant::now();
+                fault = ntag.reader.health_check().err().map(|e| e.to_string());
+            }
+            if let Some(fault) = fault {
+                let recovered = recover(&mut ntag, &health, fault);
+                drop(ntag);
+                if !recovered {
+                    sleep(RECOVERY_BACKOFF).await;
+                }
+                continue;
+            }
+            let reader_errors = ntag.reader_errors;
+            health.send_if_modified(|health| {
+                let modified = health.consecutive_errors != reader_errors;
+                health.consecutive_errors = reader_errors;
+                modified
+            });
+
+            let uid = uid.map(|uid| uid.as_bytes().to_vec());
+            // a full channel would otherwise keep the reader locked for whoever is consuming it
+            drop(ntag);
 
-            match tx.send(uid).await {
+            match tx.send(uid.clone()).await {
                 Ok(_) => {}
                 Err(_) => error!("error sending into channel"),
             };
-            sleep(Duration::from_millis(500)).await;
+
+            // changes get confirmed quickly, after that we only need to notice the next one
+            let interval = match uid == previous {
+                true => POLL_INTERVAL,
+                false => CONFIRMATION_INTERVAL,
+            };
+            previous = uid;
+
+            match (&mut card_detect, &previous) {
+                (Some(card_detect), None) => card_detect.wait(&ntag_tx, interval).await,
+                _ => sleep(interval).await,
+            }
         }
     });
-
-    Ok(())
 }
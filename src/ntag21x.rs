@@ -0,0 +1,1015 @@
+use crate::ndef::{Message, Record};
+use crate::originality;
+use crate::reader::{self, Reader, Uid};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{debug, error, info, warn};
+
+#[allow(dead_code)]
+mod constants {
+    pub const PAGE_SIZE_BYTES: usize = 4;
+    pub const BLOCK_SIZE_BYTES: usize = 16;
+    pub const BLOCK_PAGE_OFFSET: usize = BLOCK_SIZE_BYTES / PAGE_SIZE_BYTES;
+    /// Page count of the largest family member, NTAG216
+    pub const MAX_PAGE_COUNT: usize = 231;
+    pub const MAX_TOTAL_BYTES_COUNT: usize = MAX_PAGE_COUNT * PAGE_SIZE_BYTES;
+
+    // memory region offsets shared by the whole family (ends are inclusive)
+    pub const UID_START: usize = 0;
+    pub const UID_END: usize = 8;
+
+    pub const INTERNAL_START: usize = 9;
+    pub const INTERNAL_END: usize = 9;
+
+    pub const LOCK_BYTES_START: usize = 10;
+    pub const LOCK_BYTES_END: usize = 11;
+
+    pub const CAPABILITY_CONTAINER_START: usize = 12;
+    pub const CAPABILITY_CONTAINER_END: usize = 15;
+
+    pub const USER_MEMORY_START: usize = 16;
+
+    pub const STATIC_LOCK_BYTES_START: usize = LOCK_BYTES_START;
+    pub const CC_MEMORY_SIZE: usize = CAPABILITY_CONTAINER_START + 2;
+    pub const CC_WRITE_ACCESS: usize = CAPABILITY_CONTAINER_END;
+
+    pub const USER_MEMORY_FIRST_PAGE: usize = USER_MEMORY_START / PAGE_SIZE_BYTES;
+    /// Empty NDEF message TLV and terminator, the way blank tags are formatted
+    pub const EMPTY_NDEF_TLV: [u8; PAGE_SIZE_BYTES] = [0x03, 0x00, 0xfe, 0x00];
+
+    // the configuration area follows user memory, these are page offsets from its last page
+    pub const DYNAMIC_LOCK_PAGE_OFFSET: usize = 1;
+    pub const CFG_0_PAGE_OFFSET: usize = 2;
+    pub const CFG_1_PAGE_OFFSET: usize = 3;
+    pub const PWD_PAGE_OFFSET: usize = 4;
+    pub const PACK_PAGE_OFFSET: usize = 5;
+
+    // GET_VERSION response
+    pub const VERSION_LEN: usize = 8;
+    pub const VERSION_VENDOR_NXP: u8 = 0x04;
+    pub const VERSION_PRODUCT_TYPE_NTAG: u8 = 0x04;
+    pub const VERSION_STORAGE_SIZE: usize = 6;
+
+    // NTAG commands
+    pub const CMD_GET_VERSION: u8 = 0x60;
+    pub const CMD_READ: u8 = 0x30;
+    pub const CMD_FAST_READ: u8 = 0x3a;
+
+    /// The MFRC522 FIFO holds 64 bytes, so 15 pages plus CRC is the most one FAST_READ can return
+    pub const FAST_READ_MAX_PAGES: usize = 15;
+    pub const READ_RETRIES: usize = 3;
+
+    /// Pages read before looking at the TLVs: UID, lock bytes, CC and the start of user memory
+    pub const HEADER_PAGE_COUNT: usize = 8;
+    pub const CMD_WRITE: u8 = 0xa2;
+    pub const CMD_PWD_AUTH: u8 = 0x1b;
+    pub const CMD_READ_SIG: u8 = 0x3c;
+    /// READ_SIG takes an address byte, which is always 0 for NTAG21x
+    pub const READ_SIG_ADDRESS: u8 = 0x00;
+
+    pub const STATIC_LOCK_PAGE: usize = STATIC_LOCK_BYTES_START / PAGE_SIZE_BYTES;
+    pub const CAPABILITY_CONTAINER_PAGE: usize = CAPABILITY_CONTAINER_START / PAGE_SIZE_BYTES;
+    /// Static lock bits for pages 3-15, leaving the block-locking bits alone
+    pub const STATIC_LOCK_ALL: [u8; 2] = [0xf8, 0xff];
+    /// CC write access value for read-only NDEF data
+    pub const CC_WRITE_ACCESS_NONE: u8 = 0x0f;
+
+    // configuration pages
+    pub const CFG_0_AUTH0: usize = 3;
+    pub const CFG_1_ACCESS: usize = 0;
+    pub const ACCESS_PROT: u8 = 0x80;
+    pub const ACCESS_CFGLCK: u8 = 0x40;
+    /// AUTH0 past the end of memory disables password protection
+    pub const AUTH0_DISABLED: u8 = 0xff;
+    pub const PACK_LEN: usize = 2;
+}
+
+pub type Password = [u8; constants::PAGE_SIZE_BYTES];
+
+/// Password protection settings, written to the configuration pages.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordConfig {
+    pub password: Password,
+    /// Returned by the tag on successful authentication
+    pub pack: [u8; constants::PACK_LEN],
+    /// First page that needs the password
+    pub auth0: u8,
+    /// Require the password for reading as well, such cards can't be played anymore
+    pub protect_reads: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TagType {
+    NTAG213,
+    NTAG215,
+    NTAG216,
+}
+
+impl TagType {
+    /// Identifies the tag from its GET_VERSION response.
+    fn from_version(version: &[u8]) -> Option<Self> {
+        if version.len() != constants::VERSION_LEN
+            || version[1] != constants::VERSION_VENDOR_NXP
+            || version[2] != constants::VERSION_PRODUCT_TYPE_NTAG
+        {
+            return None;
+        }
+
+        match version[constants::VERSION_STORAGE_SIZE] {
+            0x0f => Some(TagType::NTAG213),
+            0x11 => Some(TagType::NTAG215),
+            0x13 => Some(TagType::NTAG216),
+            _ => None,
+        }
+    }
+
+    /// Identifies the tag from the data area size in its capability container.
+    fn from_capability_container(memory_size: u8) -> Option<Self> {
+        match memory_size {
+            0x12 => Some(TagType::NTAG213),
+            0x3e => Some(TagType::NTAG215),
+            0x6d => Some(TagType::NTAG216),
+            _ => None,
+        }
+    }
+
+    pub fn page_count(&self) -> usize {
+        match self {
+            TagType::NTAG213 => 45,
+            TagType::NTAG215 => 135,
+            TagType::NTAG216 => 231,
+        }
+    }
+
+    pub fn total_bytes_count(&self) -> usize {
+        self.page_count() * constants::PAGE_SIZE_BYTES
+    }
+
+    pub fn user_memory_last_page(&self) -> usize {
+        match self {
+            TagType::NTAG213 => 39,
+            TagType::NTAG215 => 129,
+            TagType::NTAG216 => 225,
+        }
+    }
+
+    /// Last byte of user memory (inclusive).
+    pub fn user_memory_end(&self) -> usize {
+        (self.user_memory_last_page() + 1) * constants::PAGE_SIZE_BYTES - 1
+    }
+
+    pub fn user_memory_bytes_count(&self) -> usize {
+        self.user_memory_end() - constants::USER_MEMORY_START + 1
+    }
+
+    pub fn dynamic_lock_page(&self) -> usize {
+        self.user_memory_last_page() + constants::DYNAMIC_LOCK_PAGE_OFFSET
+    }
+
+    pub fn cfg_0_page(&self) -> usize {
+        self.user_memory_last_page() + constants::CFG_0_PAGE_OFFSET
+    }
+
+    pub fn cfg_1_page(&self) -> usize {
+        self.user_memory_last_page() + constants::CFG_1_PAGE_OFFSET
+    }
+
+    pub fn pwd_page(&self) -> usize {
+        self.user_memory_last_page() + constants::PWD_PAGE_OFFSET
+    }
+
+    pub fn pack_page(&self) -> usize {
+        self.user_memory_last_page() + constants::PACK_PAGE_OFFSET
+    }
+
+    /// Pages covered by each bit of the dynamic lock bytes.
+    pub fn dynamic_lock_pages_per_bit(&self) -> usize {
+        match self {
+            TagType::NTAG213 => 2,
+            TagType::NTAG215 | TagType::NTAG216 => 16,
+        }
+    }
+
+    /// Dynamic lock bits needed to cover user memory past page 15.
+    pub fn dynamic_lock_bit_count(&self) -> usize {
+        (self.user_memory_last_page() + 1 - 16).div_ceil(self.dynamic_lock_pages_per_bit())
+    }
+}
+
+/// Outcome of checking NXP's originality signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Originality {
+    Genuine,
+    Invalid,
+    /// The tag doesn't answer READ_SIG, which is common for clones
+    Unavailable,
+}
+
+#[derive(Debug, Clone)]
+pub struct TagInfo {
+    pub uid: Vec<u8>,
+    pub tag_type: TagType,
+    /// `None` until the signature has been checked, which happens after playback started
+    pub originality: Option<Originality>,
+}
+
+/// Everything there is to know about a tag, for debugging bad cards. Feeding it back into
+/// `SimulatedReader::from_dump` recreates the tag.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagDump {
+    pub uid: String,
+    pub tag_type: TagType,
+    pub capability_container: String,
+    pub static_lock_bytes: String,
+    pub dynamic_lock_bytes: String,
+    /// One hex string per page, PWD and PACK always read back as zeros
+    pub pages: Vec<String>,
+    /// `None` if user memory doesn't hold a valid NDEF message
+    #[serde(skip_deserializing)]
+    pub records: Option<Vec<Record>>,
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    NoTag,
+    UnsupportedTag,
+    Communication(reader::Error),
+    Crc,
+    Nak(u8),
+    Timeout,
+    PartialRead { expected: usize, received: usize },
+    InvalidNdef,
+}
+
+impl ReadError {
+    /// Whether trying again has a chance of succeeding, as opposed to the tag itself being unusable.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ReadError::Communication(_)
+                | ReadError::Crc
+                | ReadError::Nak(_)
+                | ReadError::Timeout
+                | ReadError::PartialRead { .. }
+        )
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::NoTag => write!(f, "no tag present"),
+            ReadError::UnsupportedTag => write!(f, "tag is not a supported NTAG21x"),
+            ReadError::Communication(e) => write!(f, "error communicating with tag: {e}"),
+            ReadError::Crc => write!(f, "CRC error"),
+            ReadError::Nak(code) => write!(f, "tag answered with NAK {code:#x}"),
+            ReadError::Timeout => write!(f, "timeout waiting for tag"),
+            ReadError::PartialRead { expected, received } => {
+                write!(f, "expected {expected} bytes, received {received}")
+            }
+            ReadError::InvalidNdef => write!(f, "no valid NDEF message on tag"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<reader::Error> for ReadError {
+    fn from(e: reader::Error) -> Self {
+        match e {
+            reader::Error::Crc => ReadError::Crc,
+            reader::Error::Nak(code) => ReadError::Nak(code),
+            reader::Error::Timeout => ReadError::Timeout,
+            e => ReadError::Communication(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    NoTag,
+    UnsupportedTag,
+    TooLarge { required: usize, available: usize },
+    Locked,
+    ProtectedPage(usize),
+    AuthenticationFailed,
+    ConfigurationLocked,
+    Communication(reader::Error),
+    Read(ReadError),
+    VerificationFailed,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::NoTag => write!(f, "no tag present"),
+            WriteError::UnsupportedTag => write!(f, "tag is not a supported NTAG21x"),
+            WriteError::TooLarge {
+                required,
+                available,
+            } => write!(
+                f,
+                "message needs {required} bytes, tag only has {available} bytes"
+            ),
+            WriteError::Locked => write!(f, "tag is write protected"),
+            WriteError::ProtectedPage(page) => {
+                write!(f, "refusing to write page {page} outside of user memory")
+            }
+            WriteError::AuthenticationFailed => write!(f, "tag rejected the password"),
+            WriteError::ConfigurationLocked => write!(f, "tag configuration is locked"),
+            WriteError::Communication(e) => write!(f, "error communicating with tag: {e}"),
+            WriteError::Read(e) => write!(f, "error reading tag: {e}"),
+            WriteError::VerificationFailed => write!(f, "read back data doesn't match"),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+impl From<reader::Error> for WriteError {
+    fn from(e: reader::Error) -> Self {
+        WriteError::Communication(e)
+    }
+}
+
+impl From<ReadError> for WriteError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::NoTag => WriteError::NoTag,
+            ReadError::UnsupportedTag => WriteError::UnsupportedTag,
+            e => WriteError::Read(e),
+        }
+    }
+}
+
+pub struct WriteSummary {
+    pub uid: Vec<u8>,
+    pub tag_type: TagType,
+    pub bytes: usize,
+}
+
+pub struct NTAG21x<R: Reader> {
+    pub reader: R,
+    pub memory: [u8; constants::MAX_TOTAL_BYTES_COUNT],
+    pub tag_type: Option<TagType>,
+    /// Reader faults in a row, reset by anything the reader handled properly
+    pub reader_errors: usize,
+    pub last_reader_error: Option<String>,
+}
+
+impl<R: Reader> NTAG21x<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            memory: [0; constants::MAX_TOTAL_BYTES_COUNT],
+            tag_type: None,
+            reader_errors: 0,
+            last_reader_error: None,
+        }
+    }
+
+    pub fn select(&mut self) -> Option<Uid> {
+        let atqa = match self.reader.reqa() {
+            Ok(atqa) => Some(atqa),
+            Err(e) => {
+                self.track_error(&e);
+                self.reader.hlta().ok();
+                match self.reader.wupa() {
+                    Ok(atqa) => Some(atqa),
+                    Err(e) => {
+                        self.track_error(&e);
+                        None
+                    }
+                }
+            }
+        };
+
+        match atqa {
+            Some(atqa) => match self.reader.select(&atqa) {
+                Ok(uid) => {
+                    self.reader_errors = 0;
+                    Some(uid)
+                }
+                Err(e) => {
+                    self.track_error(&e);
+                    None
+                }
+            },
+            None => None,
+        }
+    }
+
+    fn track_error(&mut self, e: &reader::Error) {
+        if !matches!(e, reader::Error::Timeout) {
+            error!("error in reader comms: {e}");
+        }
+        if e.is_reader_fault() {
+            self.reader_errors += 1;
+            self.last_reader_error = Some(e.to_string());
+        } else {
+            self.reader_errors = 0;
+        }
+    }
+
+    /// Resets the reader, forgetting about the current tag.
+    pub fn recover(&mut self) -> Result<(), reader::Error> {
+        self.clear();
+        self.reader.reset()?;
+        self.reader_errors = 0;
+        Ok(())
+    }
+
+    pub fn is_token_present(&mut self) -> Option<Uid> {
+        self.select()
+    }
+
+    /// Reads the capability container and TLV header first, then only the pages the NDEF
+    /// message actually occupies.
+    pub fn read(&mut self) -> Result<Message, ReadError> {
+        // never let a previous tag's bytes leak into this read
+        self.clear();
+
+        self.select().ok_or(ReadError::NoTag)?;
+        let tag_type = self.detect_tag_type()?;
+
+        let result = self.read_message(tag_type);
+        if result.is_err() {
+            self.clear();
+        }
+        result
+    }
+
+    /// Checks whether the selected tag is a genuine NXP one.
+    pub fn originality(&mut self) -> Result<Originality, ReadError> {
+        let uid = self.select().ok_or(ReadError::NoTag)?;
+
+        let originality = match self.read_signature() {
+            Ok(signature) if originality::is_genuine(uid.as_bytes(), &signature) => {
+                Originality::Genuine
+            }
+            Ok(_) => Originality::Invalid,
+            Err(e) => {
+                debug!("READ_SIG failed: {e}");
+                // a NAK sends the tag back to idle, so it needs to be selected again
+                self.select().ok_or(ReadError::NoTag)?;
+                Originality::Unavailable
+            }
+        };
+        debug!(?originality, "checked NTAG originality signature");
+
+        Ok(originality)
+    }
+
+    fn read_signature(&mut self) -> Result<Vec<u8>, ReadError> {
+        self.with_retries(
+            &[constants::CMD_READ_SIG, constants::READ_SIG_ADDRESS],
+            originality::SIGNATURE_LEN,
+        )
+    }
+
+    /// Reads all of the tag's memory, which fails for tags with read protection.
+    pub fn dump(&mut self) -> Result<TagDump, ReadError> {
+        self.clear();
+
+        let uid = self.select().ok_or(ReadError::NoTag)?;
+        let tag_type = self.detect_tag_type()?;
+        self.read_pages(0, tag_type.page_count() - 1)?;
+
+        let memory = &self.memory[..tag_type.total_bytes_count()];
+        let dynamic_lock = tag_type.dynamic_lock_page() * constants::PAGE_SIZE_BYTES;
+        let user_memory = &memory[constants::USER_MEMORY_START..=tag_type.user_memory_end()];
+        Ok(TagDump {
+            uid: hex::encode(uid.as_bytes()),
+            tag_type,
+            capability_container: hex::encode(
+                &memory
+                    [constants::CAPABILITY_CONTAINER_START..=constants::CAPABILITY_CONTAINER_END],
+            ),
+            static_lock_bytes: hex::encode(
+                &memory[constants::LOCK_BYTES_START..=constants::LOCK_BYTES_END],
+            ),
+            // the last byte of the page is reserved
+            dynamic_lock_bytes: hex::encode(&memory[dynamic_lock..dynamic_lock + 3]),
+            pages: memory
+                .chunks(constants::PAGE_SIZE_BYTES)
+                .map(hex::encode)
+                .collect(),
+            records: Message::parse(user_memory).map(|message| message.records),
+        })
+    }
+
+    /// Forgets everything about the last tag.
+    pub fn clear(&mut self) {
+        self.memory.fill(0);
+        self.tag_type = None;
+    }
+
+    fn read_message(&mut self, tag_type: TagType) -> Result<Message, ReadError> {
+        let user_memory_end = tag_type.user_memory_end();
+        let header_end = constants::HEADER_PAGE_COUNT * constants::PAGE_SIZE_BYTES - 1;
+        self.read_pages(0, constants::HEADER_PAGE_COUNT - 1)?;
+
+        // lots of TLVs before the message can push the header past what we have read so far,
+        // in which case we just read all of user memory
+        let message_end =
+            match Message::parse_header(&self.memory[constants::USER_MEMORY_START..=header_end]) {
+                Some(header) => (constants::USER_MEMORY_START + header.end()).min(user_memory_end),
+                None => user_memory_end,
+            };
+
+        let last_page = message_end / constants::PAGE_SIZE_BYTES;
+        if last_page >= constants::HEADER_PAGE_COUNT {
+            self.read_pages(constants::HEADER_PAGE_COUNT, last_page)?;
+        }
+        debug!(pages = last_page + 1, "read NTAG");
+
+        let user_memory = &self.memory[constants::USER_MEMORY_START..=user_memory_end];
+        Message::parse(user_memory).ok_or(ReadError::InvalidNdef)
+    }
+
+    /// Figures out which family member is selected, preferring GET_VERSION and falling back
+    /// to the capability container for tags that don't implement it.
+    fn detect_tag_type(&mut self) -> Result<TagType, ReadError> {
+        let tag_type = match self.get_version() {
+            Ok(version) => TagType::from_version(&version),
+            Err(e) => {
+                debug!("GET_VERSION failed: {e}");
+                // a NAK sends the tag back to idle, so it needs to be selected again
+                self.select().ok_or(ReadError::NoTag)?;
+                None
+            }
+        };
+
+        let tag_type = match tag_type {
+            Some(tag_type) => Some(tag_type),
+            None => {
+                let block = self.read_block(0)?;
+                TagType::from_capability_container(block[constants::CC_MEMORY_SIZE])
+            }
+        };
+
+        match tag_type {
+            Some(tag_type) => debug!(?tag_type, "detected tag type"),
+            None => error!("unsupported tag type"),
+        }
+
+        self.tag_type = tag_type;
+        tag_type.ok_or(ReadError::UnsupportedTag)
+    }
+
+    fn get_version(&mut self) -> Result<Vec<u8>, reader::Error> {
+        self.reader.transceive_crc(&[constants::CMD_GET_VERSION])
+    }
+
+    /// Selects the tag, authenticates if it needs a password and reads all of its memory, to
+    /// have an up to date view of the lock bytes, capability container and configuration
+    /// before touching anything.
+    fn prepare_write(&mut self, password: Option<Password>) -> Result<(Uid, TagType), WriteError> {
+        let uid = self.select().ok_or(WriteError::NoTag)?;
+        let tag_type = self.detect_tag_type()?;
+
+        if let Some(password) = password {
+            self.authenticate(password)?;
+        }
+        self.read_pages(0, tag_type.page_count() - 1)?;
+
+        Ok((uid, tag_type))
+    }
+
+    /// Sends PWD_AUTH, which unlocks the protected pages until the tag leaves the field.
+    fn authenticate(&mut self, password: Password) -> Result<(), WriteError> {
+        let mut tx = vec![constants::CMD_PWD_AUTH];
+        tx.extend(password);
+        match self.reader.transceive_crc(&tx) {
+            Ok(pack) if pack.len() == constants::PACK_LEN => Ok(()),
+            Ok(_) => Err(WriteError::AuthenticationFailed),
+            Err(reader::Error::Nak(_)) => Err(WriteError::AuthenticationFailed),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the message into user memory and reads it back for verification.
+    pub fn write(
+        &mut self,
+        message: &Message,
+        password: Option<Password>,
+    ) -> Result<WriteSummary, WriteError> {
+        let data = message.to_tlv();
+        let (uid, tag_type) = self.prepare_write(password)?;
+        if data.len() > tag_type.user_memory_bytes_count() {
+            return Err(WriteError::TooLarge {
+                required: data.len(),
+                available: tag_type.user_memory_bytes_count(),
+            });
+        }
+
+        let page_count = data.len().div_ceil(constants::PAGE_SIZE_BYTES);
+        let pages =
+            constants::USER_MEMORY_FIRST_PAGE..constants::USER_MEMORY_FIRST_PAGE + page_count;
+        if self.is_read_only()
+            || pages
+                .clone()
+                .any(|page| self.is_page_locked(tag_type, page))
+        {
+            return Err(WriteError::Locked);
+        }
+
+        let page_data = data
+            .chunks(constants::PAGE_SIZE_BYTES)
+            .map(|chunk| {
+                let mut page_data = [0u8; constants::PAGE_SIZE_BYTES];
+                page_data[..chunk.len()].copy_from_slice(chunk);
+                page_data
+            })
+            .collect::<Vec<_>>();
+
+        // the TLV header goes in last, so a tag pulled away halfway through holds an empty
+        // message instead of a length pointing at half written records
+        self.write_page(constants::USER_MEMORY_FIRST_PAGE, constants::EMPTY_NDEF_TLV)?;
+        for (page_offset, page_data) in page_data.iter().enumerate().skip(1) {
+            self.write_page(constants::USER_MEMORY_FIRST_PAGE + page_offset, *page_data)?;
+        }
+        self.write_page(constants::USER_MEMORY_FIRST_PAGE, page_data[0])?;
+
+        self.read_pages(0, tag_type.user_memory_last_page())?;
+        let written =
+            &self.memory[constants::USER_MEMORY_START..constants::USER_MEMORY_START + data.len()];
+        if written != data.as_slice() {
+            error!("NTAG verification after write failed");
+            return Err(WriteError::VerificationFailed);
+        }
+
+        info!(bytes = data.len(), ?tag_type, "wrote NDEF message to NTAG");
+        Ok(WriteSummary {
+            uid: uid.as_bytes().to_vec(),
+            tag_type,
+            bytes: data.len(),
+        })
+    }
+
+    /// Sets up password protection, `current` being the password the tag is protected with
+    /// already, if any.
+    pub fn set_password(
+        &mut self,
+        config: &PasswordConfig,
+        current: Option<Password>,
+    ) -> Result<Uid, WriteError> {
+        let (uid, tag_type) = self.prepare_write(current)?;
+        let mut cfg_0 = self.page(tag_type.cfg_0_page());
+        let mut cfg_1 = self.page(tag_type.cfg_1_page());
+        if cfg_1[constants::CFG_1_ACCESS] & constants::ACCESS_CFGLCK != 0 {
+            return Err(WriteError::ConfigurationLocked);
+        }
+
+        let mut pack = [0u8; constants::PAGE_SIZE_BYTES];
+        pack[..constants::PACK_LEN].copy_from_slice(&config.pack);
+        self.write_page_unchecked(tag_type.pwd_page(), config.password)?;
+        self.write_page_unchecked(tag_type.pack_page(), pack)?;
+
+        match config.protect_reads {
+            true => cfg_1[constants::CFG_1_ACCESS] |= constants::ACCESS_PROT,
+            false => cfg_1[constants::CFG_1_ACCESS] &= !constants::ACCESS_PROT,
+        }
+        self.write_page_unchecked(tag_type.cfg_1_page(), cfg_1)?;
+        // AUTH0 last, so the tag is never protected by a half written password
+        cfg_0[constants::CFG_0_AUTH0] = config.auth0;
+        self.write_page_unchecked(tag_type.cfg_0_page(), cfg_0)?;
+
+        info!(auth0 = config.auth0, ?tag_type, "set NTAG password");
+        Ok(uid)
+    }
+
+    /// Turns password protection off again.
+    pub fn remove_password(&mut self, password: Password) -> Result<Uid, WriteError> {
+        let (uid, tag_type) = self.prepare_write(Some(password))?;
+        let mut cfg_0 = self.page(tag_type.cfg_0_page());
+        let mut cfg_1 = self.page(tag_type.cfg_1_page());
+        if cfg_1[constants::CFG_1_ACCESS] & constants::ACCESS_CFGLCK != 0 {
+            return Err(WriteError::ConfigurationLocked);
+        }
+
+        cfg_0[constants::CFG_0_AUTH0] = constants::AUTH0_DISABLED;
+        self.write_page_unchecked(tag_type.cfg_0_page(), cfg_0)?;
+        cfg_1[constants::CFG_1_ACCESS] &= !constants::ACCESS_PROT;
+        self.write_page_unchecked(tag_type.cfg_1_page(), cfg_1)?;
+
+        info!(?tag_type, "removed NTAG password");
+        Ok(uid)
+    }
+
+    /// Makes the tag permanently read-only by marking the capability container read-only and
+    /// setting all static and dynamic lock bits. There is no way back from this.
+    pub fn lock(&mut self, password: Option<Password>) -> Result<Uid, WriteError> {
+        let (uid, tag_type) = self.prepare_write(password)?;
+
+        let mut dynamic_lock = self.page(tag_type.dynamic_lock_page());
+        for bit in 0..tag_type.dynamic_lock_bit_count() {
+            dynamic_lock[bit / 8] |= 1 << (bit % 8);
+        }
+        self.write_page_unchecked(tag_type.dynamic_lock_page(), dynamic_lock)?;
+
+        let mut capability_container = self.page(constants::CAPABILITY_CONTAINER_PAGE);
+        capability_container[constants::CC_WRITE_ACCESS % constants::PAGE_SIZE_BYTES] =
+            constants::CC_WRITE_ACCESS_NONE;
+        self.write_page_unchecked(constants::CAPABILITY_CONTAINER_PAGE, capability_container)?;
+
+        // the static lock bits cover the capability container, so they go last
+        let mut static_lock = self.page(constants::STATIC_LOCK_PAGE);
+        static_lock[2..].copy_from_slice(&constants::STATIC_LOCK_ALL);
+        self.write_page_unchecked(constants::STATIC_LOCK_PAGE, static_lock)?;
+
+        info!(?tag_type, "locked NTAG");
+        Ok(uid)
+    }
+
+    fn page(&self, page: usize) -> [u8; constants::PAGE_SIZE_BYTES] {
+        let start = page * constants::PAGE_SIZE_BYTES;
+        self.memory[start..start + constants::PAGE_SIZE_BYTES]
+            .try_into()
+            .unwrap()
+    }
+
+    /// Whether the capability container marks the NDEF data as read-only.
+    fn is_read_only(&self) -> bool {
+        self.memory[constants::CC_WRITE_ACCESS] & 0x0f != 0
+    }
+
+    fn is_page_locked(&self, tag_type: TagType, page: usize) -> bool {
+        let static_lock = &self.memory[constants::STATIC_LOCK_BYTES_START..];
+        let dynamic_lock =
+            &self.memory[tag_type.dynamic_lock_page() * constants::PAGE_SIZE_BYTES..];
+
+        match page {
+            // the first static lock byte covers pages 3-7 in its upper bits, the second pages 8-15
+            3..=7 => static_lock[0] & (1 << page) != 0,
+            8..=15 => static_lock[1] & (1 << (page - 8)) != 0,
+            _ => {
+                let bit = (page - 16) / tag_type.dynamic_lock_pages_per_bit();
+                dynamic_lock[bit / 8] & (1 << (bit % 8)) != 0
+            }
+        }
+    }
+
+    /// Writes a single page, refusing anything outside of user memory so the UID,
+    /// lock bytes, capability container and configuration can't be clobbered.
+    pub fn write_page(
+        &mut self,
+        page: usize,
+        data: [u8; constants::PAGE_SIZE_BYTES],
+    ) -> Result<(), WriteError> {
+        let tag_type = self.tag_type.ok_or(WriteError::UnsupportedTag)?;
+        if !(constants::USER_MEMORY_FIRST_PAGE..=tag_type.user_memory_last_page()).contains(&page) {
+            return Err(WriteError::ProtectedPage(page));
+        }
+
+        self.write_page_unchecked(page, data)
+    }
+
+    /// Writes any page, including the lock bytes and configuration.
+    fn write_page_unchecked(
+        &mut self,
+        page: usize,
+        data: [u8; constants::PAGE_SIZE_BYTES],
+    ) -> Result<(), WriteError> {
+        let mut tx = vec![constants::CMD_WRITE, page as u8];
+        tx.extend(data);
+        match self.reader.transceive_ack(&tx) {
+            Ok(_) => Ok(()),
+            // the tag NAKs writes to locked or password protected pages
+            Err(reader::Error::Nak(_)) => Err(WriteError::Locked),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_block(&mut self, page_addr: usize) -> Result<Vec<u8>, ReadError> {
+        let page_addr = u8::try_from(page_addr).expect("Tried to read out of bound block!");
+        self.with_retries(
+            &[constants::CMD_READ, page_addr],
+            constants::BLOCK_SIZE_BYTES,
+        )
+    }
+
+    /// Sends a read command, retrying transient failures. A NAK resets the tag to idle,
+    /// so it gets selected again before the next attempt.
+    fn with_retries(&mut self, command: &[u8], expected: usize) -> Result<Vec<u8>, ReadError> {
+        let mut attempt = 1;
+        loop {
+            let error = match self.reader.transceive_crc(command) {
+                Ok(data) if data.len() == expected => return Ok(data),
+                Ok(data) => ReadError::PartialRead {
+                    expected,
+                    received: data.len(),
+                },
+                Err(e) => ReadError::from(e),
+            };
+
+            if attempt >= constants::READ_RETRIES || !error.is_transient() {
+                return Err(error);
+            }
+            warn!(attempt, "retrying NTAG read: {error}");
+            attempt += 1;
+
+            if matches!(error, ReadError::Nak(_)) {
+                self.select().ok_or(ReadError::NoTag)?;
+            }
+        }
+    }
+
+    /// Reads the inclusive page range into `memory` using as few FAST_READs as possible.
+    fn read_pages(&mut self, first_page: usize, last_page: usize) -> Result<(), ReadError> {
+        for chunk_start in (first_page..=last_page).step_by(constants::FAST_READ_MAX_PAGES) {
+            let chunk_end = (chunk_start + constants::FAST_READ_MAX_PAGES - 1).min(last_page);
+            let start = chunk_start * constants::PAGE_SIZE_BYTES;
+            let end = (chunk_end + 1) * constants::PAGE_SIZE_BYTES;
+
+            let data = self.with_retries(
+                &[constants::CMD_FAST_READ, chunk_start as u8, chunk_end as u8],
+                end - start,
+            )?;
+            self.memory[start..end].copy_from_slice(&data);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ndef::Record;
+    use crate::ntag21x::*;
+    use crate::simulated_reader::SimulatedReader;
+
+    const UID: [u8; 7] = [0x04, 0x8a, 0x3b, 0x12, 0x5c, 0x61, 0x80];
+
+    /// A factory fresh NTAG215 with `data` in user memory.
+    fn ntag215_dump(data: &[u8]) -> Vec<u8> {
+        let mut pages = vec![0u8; TagType::NTAG215.total_bytes_count()];
+        pages[0..3].copy_from_slice(&UID[..3]);
+        pages[4..8].copy_from_slice(&UID[3..]);
+        pages[12..16].copy_from_slice(&[0xe1, 0x10, 0x3e, 0x00]);
+        pages[16..16 + data.len()].copy_from_slice(data);
+        // password protection starts past the end of memory
+        let cfg_0 = TagType::NTAG215.cfg_0_page() * 4;
+        pages[cfg_0 + 3] = 0xff;
+        pages
+    }
+
+    fn uri_message(uri: &str) -> Message {
+        Message::new(vec![Record::URI {
+            uri: String::from(uri),
+        }])
+        .unwrap()
+    }
+
+    #[test]
+    fn detect_tag_type() {
+        const NTAG216_VERSION: [u8; 8] = [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x13, 0x03];
+
+        assert_eq!(
+            TagType::from_version(&NTAG216_VERSION),
+            Some(TagType::NTAG216)
+        );
+        assert_eq!(
+            TagType::from_capability_container(0x12),
+            Some(TagType::NTAG213)
+        );
+        assert_eq!(TagType::from_capability_container(0x00), None);
+
+        assert_eq!(TagType::NTAG213.user_memory_bytes_count(), 144);
+        assert_eq!(TagType::NTAG215.user_memory_bytes_count(), 504);
+        assert_eq!(TagType::NTAG216.user_memory_bytes_count(), 888);
+        assert_eq!(TagType::NTAG215.pwd_page(), 133);
+    }
+
+    #[test]
+    fn read_message() {
+        let message = uri_message("https://open.spotify.com/album/4Gfnly5CzMJQqkUFfoHaP3");
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&message.to_tlv())));
+
+        assert_eq!(ntag.read().unwrap().records, message.records);
+        assert_eq!(ntag.tag_type, Some(TagType::NTAG215));
+        // a second read has to deal with the tag still being selected
+        assert_eq!(ntag.read().unwrap().records, message.records);
+    }
+
+    #[test]
+    fn read_retries_crc_errors() {
+        let message = uri_message("file://./audio/police_s.wav");
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&message.to_tlv())));
+
+        ntag.reader.inject_crc_errors(constants::READ_RETRIES - 1);
+        assert_eq!(ntag.read().unwrap().records, message.records);
+
+        // GET_VERSION isn't retried, the tag type then comes from the capability container
+        ntag.reader.inject_crc_errors(constants::READ_RETRIES + 1);
+        assert!(matches!(ntag.read(), Err(ReadError::Crc)));
+        assert!(ntag.memory.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&[0x03, 0x00, 0xfe])));
+        // an empty NDEF message is a valid read
+        assert_eq!(ntag.read().unwrap().records, vec![]);
+
+        ntag.reader.set_present(false);
+        assert!(matches!(ntag.read(), Err(ReadError::NoTag)));
+        assert_eq!(ntag.tag_type, None);
+
+        // a blank tag without any TLVs
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&[])));
+        assert!(matches!(ntag.read(), Err(ReadError::InvalidNdef)));
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&[])));
+        let message = uri_message("https://open.spotify.com/playlist/62Q9JugytREDtl4i4fcHfX");
+
+        let summary = ntag.write(&message, None).unwrap();
+        assert_eq!(summary.uid, UID);
+        assert_eq!(summary.bytes, message.to_tlv().len());
+        assert_eq!(ntag.read().unwrap().records, message.records);
+    }
+
+    #[test]
+    fn interrupted_write_leaves_empty_message() {
+        let old = uri_message("https://open.spotify.com/album/4Gfnly5CzMJQqkUFfoHaP3");
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&old.to_tlv())));
+        let message = uri_message("https://open.spotify.com/playlist/62Q9JugytREDtl4i4fcHfX");
+
+        ntag.reader.remove_after_writes(3);
+        assert!(ntag.write(&message, None).is_err());
+        ntag.reader.set_present(true);
+        assert_eq!(ntag.read().unwrap().records, Vec::new());
+    }
+
+    #[test]
+    fn password_protects_writes() {
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&[])));
+        let message = uri_message("https://open.spotify.com/album/4Gfnly5CzMJQqkUFfoHaP3");
+        let config = PasswordConfig {
+            password: [0x12, 0x34, 0x56, 0x78],
+            pack: [0xab, 0xcd],
+            auth0: 4,
+            protect_reads: false,
+        };
+
+        ntag.set_password(&config, None).unwrap();
+        assert!(matches!(
+            ntag.write(&message, None),
+            Err(WriteError::Locked)
+        ));
+        assert!(matches!(
+            ntag.write(&message, Some([0; 4])),
+            Err(WriteError::AuthenticationFailed)
+        ));
+        ntag.write(&message, Some(config.password)).unwrap();
+        // reads stay open, so the card can still be played
+        assert_eq!(ntag.read().unwrap().records, message.records);
+
+        ntag.remove_password(config.password).unwrap();
+        ntag.write(&message, None).unwrap();
+    }
+
+    #[test]
+    fn lock_makes_read_only() {
+        let message = uri_message("https://open.spotify.com/album/4Gfnly5CzMJQqkUFfoHaP3");
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&message.to_tlv())));
+
+        ntag.lock(None).unwrap();
+        assert!(matches!(
+            ntag.write(&message, None),
+            Err(WriteError::Locked)
+        ));
+        assert_eq!(ntag.read().unwrap().records, message.records);
+    }
+
+    #[test]
+    fn originality() {
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&[])));
+
+        // the simulated tag isn't signed by NXP
+        assert_eq!(ntag.originality().unwrap(), Originality::Invalid);
+        ntag.reader.set_present(false);
+        assert!(matches!(ntag.originality(), Err(ReadError::NoTag)));
+    }
+
+    #[test]
+    fn dump_roundtrip() {
+        let message = uri_message("https://open.spotify.com/album/4Gfnly5CzMJQqkUFfoHaP3");
+        let mut ntag = NTAG21x::new(SimulatedReader::new(ntag215_dump(&message.to_tlv())));
+
+        let dump = ntag.dump().unwrap();
+        assert_eq!(dump.uid, hex::encode(UID));
+        assert_eq!(dump.capability_container, "e1103e00");
+        assert_eq!(dump.pages.len(), TagType::NTAG215.page_count());
+        assert_eq!(dump.records, Some(message.records.clone()));
+
+        let json = serde_json::to_string(&dump).unwrap();
+        let dump: TagDump = serde_json::from_str(&json).unwrap();
+        let mut ntag = NTAG21x::new(SimulatedReader::from_dump(&dump).unwrap());
+        assert_eq!(ntag.read().unwrap().records, message.records);
+    }
+}
@@ -0,0 +1,187 @@
+use num_bigint::BigUint;
+
+// NTAG21x tags carry an ECDSA signature over their UID, made with NXP's private key on the
+// secp128r1 curve. The UID is signed as is, without hashing.
+
+#[allow(dead_code)]
+mod constants {
+    pub const P: &str = "fffffffdffffffffffffffffffffffff";
+    pub const A: &str = "fffffffdfffffffffffffffffffffffc";
+    pub const B: &str = "e87579c11079f43dd824993c2cee5ed3";
+    pub const N: &str = "fffffffe0000000075a30d1b9038a115";
+    pub const G_X: &str = "161ff7528b899b2d0c28607ca52c5b86";
+    pub const G_Y: &str = "cf5ac8395bafeb13c02da292dded7a83";
+
+    /// NXP's public key for NTAG21x originality signatures
+    pub const NXP_PUBLIC_KEY_X: &str = "494e1a386d3d3cfe3dc10e5de68a499b";
+    pub const NXP_PUBLIC_KEY_Y: &str = "1c202db5b132393e89ed19fe5be8bc61";
+
+    pub const SIGNATURE_LEN: usize = 32;
+}
+
+pub use constants::SIGNATURE_LEN;
+
+/// Affine point, `None` being the point at infinity.
+type Point = Option<(BigUint, BigUint)>;
+
+fn parse(hex: &str) -> BigUint {
+    BigUint::parse_bytes(hex.as_bytes(), 16).expect("invalid curve constant")
+}
+
+struct Curve {
+    p: BigUint,
+    a: BigUint,
+    n: BigUint,
+    g: Point,
+}
+
+impl Curve {
+    fn secp128r1() -> Self {
+        Self {
+            p: parse(constants::P),
+            a: parse(constants::A),
+            n: parse(constants::N),
+            g: Some((parse(constants::G_X), parse(constants::G_Y))),
+        }
+    }
+
+    /// Both moduli are prime, so Fermat's little theorem gives us the inverse.
+    fn inverse(x: &BigUint, modulus: &BigUint) -> BigUint {
+        x.modpow(&(modulus - 2u8), modulus)
+    }
+
+    fn add(&self, lhs: &Point, rhs: &Point) -> Point {
+        let p = &self.p;
+        let ((x1, y1), (x2, y2)) = match (lhs, rhs) {
+            (None, _) => return rhs.clone(),
+            (_, None) => return lhs.clone(),
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+        };
+
+        let lambda = match x1 == x2 {
+            true if (y1 + y2) % p == BigUint::ZERO => return None,
+            true => (3u8 * x1 * x1 + &self.a) * Self::inverse(&(2u8 * y1 % p), p) % p,
+            false => (y2 + p - y1) * Self::inverse(&((x2 + p - x1) % p), p) % p,
+        };
+
+        let x3 = (&lambda * &lambda + 2u8 * p - x1 - x2) % p;
+        let y3 = (lambda * ((x1 + p - &x3) % p) + p - y1) % p;
+        Some((x3, y3))
+    }
+
+    fn multiply(&self, k: &BigUint, point: &Point) -> Point {
+        let mut result = None;
+        for bit in (0..k.bits()).rev() {
+            result = self.add(&result, &result);
+            if k.bit(bit) {
+                result = self.add(&result, point);
+            }
+        }
+        result
+    }
+
+    fn verify(&self, public_key: &Point, message: &[u8], signature: &[u8]) -> bool {
+        if signature.len() != constants::SIGNATURE_LEN {
+            return false;
+        }
+        let (r, s) = signature.split_at(constants::SIGNATURE_LEN / 2);
+        let r = BigUint::from_bytes_be(r);
+        let s = BigUint::from_bytes_be(s);
+        if r == BigUint::ZERO || s == BigUint::ZERO || r >= self.n || s >= self.n {
+            return false;
+        }
+
+        let e = BigUint::from_bytes_be(message);
+        let w = Self::inverse(&s, &self.n);
+        let u1 = e * &w % &self.n;
+        let u2 = &r * w % &self.n;
+        let point = self.add(
+            &self.multiply(&u1, &self.g),
+            &self.multiply(&u2, public_key),
+        );
+
+        match point {
+            Some((x, _)) => x % &self.n == r,
+            None => false,
+        }
+    }
+}
+
+/// Checks the READ_SIG response of a tag against its UID.
+pub fn is_genuine(uid: &[u8], signature: &[u8]) -> bool {
+    let public_key = Some((
+        parse(constants::NXP_PUBLIC_KEY_X),
+        parse(constants::NXP_PUBLIC_KEY_Y),
+    ));
+    Curve::secp128r1().verify(&public_key, uid, signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::originality::*;
+
+    fn on_curve(curve: &Curve, point: &Point) -> bool {
+        let (x, y) = point.as_ref().unwrap();
+        let b = parse(constants::B);
+        (y * y) % &curve.p == (x * x * x + &curve.a * x + b) % &curve.p
+    }
+
+    #[test]
+    fn verify_signature() {
+        let curve = Curve::secp128r1();
+        assert!(on_curve(&curve, &curve.g));
+        assert!(on_curve(
+            &curve,
+            &Some((
+                parse(constants::NXP_PUBLIC_KEY_X),
+                parse(constants::NXP_PUBLIC_KEY_Y)
+            ))
+        ));
+
+        // sign with a key of our own, the way NXP does at the factory
+        let private_key = parse("2b7e151628aed2a6abf7158809cf4f3c");
+        let public_key = curve.multiply(&private_key, &curve.g);
+        let uid = [0x04, 0x8a, 0x3b, 0x12, 0x5c, 0x61, 0x80];
+        let k = parse("3243f6a8885a308d313198a2e0370734");
+        let (x, _) = curve.multiply(&k, &curve.g).unwrap();
+        let r = x % &curve.n;
+        let s = Curve::inverse(&k, &curve.n) * (BigUint::from_bytes_be(&uid) + &r * private_key)
+            % &curve.n;
+        let mut signature = vec![0u8; SIGNATURE_LEN];
+        let (r, s) = (r.to_bytes_be(), s.to_bytes_be());
+        signature[16 - r.len()..16].copy_from_slice(&r);
+        signature[32 - s.len()..].copy_from_slice(&s);
+
+        assert!(curve.verify(&public_key, &uid, &signature));
+        let mut cloned = uid;
+        cloned[6] ^= 0x01;
+        assert!(!curve.verify(&public_key, &cloned, &signature));
+        assert!(!curve.verify(&public_key, &uid, &signature[..16]));
+        assert!(!is_genuine(&uid, &signature));
+    }
+
+    #[test]
+    fn genuine_tags() {
+        // READ_SIG responses of two NTAG21x tags, from the Proxmark3 recover_pk.py self tests
+        let tags = [
+            (
+                "04e10cda993c80",
+                "8b76052ee42f5567beb53238b3e3f9950707c0dcc956b5c5efcfdb709b2d82b3",
+            ),
+            (
+                "04db0bda993c80",
+                "6048efd9417cd10f6b7f1818d471a7fe5b46868d2eabdc6307a1e0aae139d8d0",
+            ),
+        ];
+        for (uid, signature) in tags {
+            let uid = hex::decode(uid).unwrap();
+            let signature = hex::decode(signature).unwrap();
+            assert!(is_genuine(&uid, &signature));
+        }
+
+        // a signature copied onto a tag with another UID
+        let uid = hex::decode(tags[1].0).unwrap();
+        let signature = hex::decode(tags[0].1).unwrap();
+        assert!(!is_genuine(&uid, &signature));
+    }
+}
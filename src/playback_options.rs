@@ -0,0 +1,119 @@
+use crate::ndef::{Message, Record};
+use std::time::Duration;
+use tracing::warn;
+use url::form_urlencoded;
+
+// Cards can carry an external record next to their URI with options for playing it, encoded
+// like a query string, e.g. `volume=40&shuffle=1&repeat=0&start=90&resume=0`.
+
+pub const RECORD_TYPE: &str = "drempelbox.org:opts";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackOptions {
+    /// Volume between 0 and 1 to start at, written as a percentage on the card
+    pub volume: Option<f64>,
+    /// Overrides the shuffle setting for this card
+    pub shuffle: Option<bool>,
+    pub repeat: bool,
+    /// Where to start when there is no resume position
+    pub start_offset: Option<Duration>,
+    /// Whether to pick up where the card was left off last time
+    pub resume: bool,
+}
+
+impl Default for PlaybackOptions {
+    fn default() -> Self {
+        Self {
+            volume: None,
+            shuffle: None,
+            repeat: false,
+            start_offset: None,
+            resume: true,
+        }
+    }
+}
+
+impl PlaybackOptions {
+    /// The options from the first options record in the message, the defaults without one.
+    pub fn from_message(message: &Message) -> Self {
+        message
+            .records
+            .iter()
+            .find_map(|record| match record {
+                Record::External {
+                    record_type,
+                    payload,
+                } if record_type == RECORD_TYPE => Some(Self::from_payload(payload)),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Unknown keys and invalid values are skipped, so older boxes can read newer cards.
+    pub fn from_payload(payload: &[u8]) -> Self {
+        let mut options = Self::default();
+        for (key, value) in form_urlencoded::parse(payload) {
+            let parsed = match key.as_ref() {
+                "volume" => value
+                    .parse::<u64>()
+                    .map(|volume| options.volume = Some(volume.min(100) as f64 / 100.0))
+                    .ok(),
+                "shuffle" => parse_bool(&value).map(|shuffle| options.shuffle = Some(shuffle)),
+                "repeat" => parse_bool(&value).map(|repeat| options.repeat = repeat),
+                "start" => value
+                    .parse::<u64>()
+                    .map(|seconds| options.start_offset = Some(Duration::from_secs(seconds)))
+                    .ok(),
+                "resume" => parse_bool(&value).map(|resume| options.resume = resume),
+                _ => None,
+            };
+            if parsed.is_none() {
+                warn!(%key, %value, "skipping invalid playback option");
+            }
+        }
+        options
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ndef::{Message, Record};
+    use crate::playback_options::*;
+
+    #[test]
+    fn parse_options() {
+        let message = Message::new(vec![
+            Record::URI {
+                uri: String::from("https://open.spotify.com/album/4Gfnly5CzMJQqkUFfoHaP3"),
+            },
+            Record::External {
+                record_type: String::from(RECORD_TYPE),
+                payload: b"volume=40&shuffle=1&start=90&resume=false&colour=red".to_vec(),
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(
+            PlaybackOptions::from_message(&message),
+            PlaybackOptions {
+                volume: Some(0.4),
+                shuffle: Some(true),
+                repeat: false,
+                start_offset: Some(Duration::from_secs(90)),
+                resume: false,
+            }
+        );
+        assert_eq!(
+            PlaybackOptions::from_payload(b"repeat=yes&volume=-3"),
+            PlaybackOptions::default()
+        );
+    }
+}
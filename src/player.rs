@@ -1,22 +1,77 @@
+use std::env;
 use std::sync::Arc;
 
+use crate::action::Action;
 use crate::amp::Amp;
 use crate::file_player::FilePlayer;
+use crate::playback_options::PlaybackOptions;
+use crate::positions::{Position, ResumePositions};
 use crate::spotify_player::SpotifyPlayer;
 use itertools::Itertools;
 use librespot::playback::config::VolumeCtrl;
 use librespot::playback::mixer;
 use librespot::playback::mixer::MixerConfig;
 use percent_encoding::percent_decode_str;
+use serde::Serialize;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinSet;
+use tokio::time::{interval, sleep_until, Duration, Instant};
 use tracing::{error, info};
 use url::Url;
 
+#[derive(Debug, Clone, Serialize)]
+pub struct NowPlaying {
+    pub url: String,
+    pub title: Option<String>,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    Spotify,
+    File,
+}
+
+/// Playback paused by removing a tag, kept around until the resume window runs out.
+struct Paused {
+    uid: Vec<u8>,
+    backend: Backend,
+    deadline: Instant,
+}
+
 #[derive(Debug)]
 pub enum PlayerRequestMessage {
     Stop,
-    URL(Url),
+    /// Pauses playback for the tag that was just removed.
+    Pause {
+        uid: Vec<u8>,
+    },
+    /// Resumes playback if it was paused for this tag within the resume window, answers
+    /// whether it did. Any other tag discards the paused playback.
+    Resume {
+        uid: Vec<u8>,
+        responder: oneshot::Sender<bool>,
+    },
+    /// Plays the URL, starting where it was left off last time. Progress is remembered per
+    /// tag UID when there is one, per URL otherwise. `drempelbox:` URLs are actions instead.
+    URL {
+        url: Url,
+        title: Option<String>,
+        uid: Option<Vec<u8>>,
+        options: PlaybackOptions,
+    },
+    /// Forgets the resume position for a tag UID (hex) or URL, answers whether there was one.
+    ResetPosition {
+        key: String,
+        responder: oneshot::Sender<bool>,
+    },
+    /// Saves the resume positions before the process exits.
+    Shutdown {
+        responder: oneshot::Sender<()>,
+    },
+    Status {
+        responder: oneshot::Sender<Option<NowPlaying>>,
+    },
     VolumeUp {
         responder: oneshot::Sender<f64>,
     },
@@ -31,6 +86,16 @@ pub enum PlayerRequestMessage {
 
 pub type Mixer = Arc<dyn mixer::Mixer>;
 
+const POSITION_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn resume_window() -> Duration {
+    let seconds = env::var("RESUME_WINDOW_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(60);
+    Duration::from_secs(seconds)
+}
+
 pub async fn start_player_task(
     join_set: &mut JoinSet<()>,
     mut receiver: mpsc::Receiver<PlayerRequestMessage>,
@@ -39,37 +104,324 @@ pub async fn start_player_task(
     let mixer: Mixer = get_mixer()?;
     let mut spotify_player = SpotifyPlayer::new(mixer.clone()).await?;
     let file_player = FilePlayer::new(mixer.clone()).await?;
+    let mut now_playing: Option<NowPlaying> = None;
+    let mut backend: Option<Backend> = None;
+    let mut paused: Option<Paused> = None;
+    let resume_window = resume_window();
+    let mut positions = ResumePositions::load().await;
+    // the key the current playback's progress is remembered under
+    let mut position_key: Option<String> = None;
+    let mut sleep_deadline: Option<Instant> = None;
+    let mut shuffle = false;
 
     join_set.spawn(async move {
+        let mut save_interval = interval(POSITION_SAVE_INTERVAL);
         loop {
-            let command = receiver.recv().await;
+            let deadline = paused.as_ref().map(|paused| paused.deadline);
+            let sleep_timer = sleep_deadline;
+            let command = tokio::select! {
+                command = receiver.recv() => command,
+                _ = save_interval.tick() => {
+                    record_position(
+                        &mut positions,
+                        &position_key,
+                        backend,
+                        &file_player,
+                        &spotify_player,
+                    )
+                    .await;
+                    save_positions(&mut positions).await;
+                    continue;
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    info!("resume window expired, stopping");
+                    record_position(
+                        &mut positions,
+                        &position_key,
+                        backend,
+                        &file_player,
+                        &spotify_player,
+                    )
+                    .await;
+                    stop(&file_player, &spotify_player, &amp).await;
+                    now_playing = None;
+                    backend = None;
+                    paused = None;
+                    position_key = None;
+                    continue;
+                }
+                _ = sleep_until(sleep_timer.unwrap_or_else(Instant::now)), if sleep_timer.is_some() => {
+                    info!("sleep timer expired, stopping");
+                    record_position(
+                        &mut positions,
+                        &position_key,
+                        backend,
+                        &file_player,
+                        &spotify_player,
+                    )
+                    .await;
+                    stop(&file_player, &spotify_player, &amp).await;
+                    now_playing = None;
+                    backend = None;
+                    paused = None;
+                    position_key = None;
+                    sleep_deadline = None;
+                    continue;
+                }
+            };
             match command {
                 Some(sink_message) => match sink_message {
                     PlayerRequestMessage::Stop => {
                         info!("received stop request");
+                        record_position(
+                            &mut positions,
+                            &position_key,
+                            backend,
+                            &file_player,
+                            &spotify_player,
+                        )
+                        .await;
                         stop(&file_player, &spotify_player, &amp).await;
+                        position_key = None;
+                        now_playing = None;
+                        backend = None;
+                        paused = None;
+                    }
+                    PlayerRequestMessage::Pause { uid } => {
+                        info!(?uid, "received pause request");
+                        match backend {
+                            Some(active) => {
+                                pause(&file_player, &spotify_player, active, &amp).await;
+                                if let Some(now_playing) = &mut now_playing {
+                                    now_playing.paused = true;
+                                }
+                                paused = Some(Paused {
+                                    uid,
+                                    backend: active,
+                                    deadline: Instant::now() + resume_window,
+                                });
+                            }
+                            None => info!("nothing playing, nothing to pause"),
+                        }
+                    }
+                    PlayerRequestMessage::Resume { uid, responder } => {
+                        let resumed = match paused.take() {
+                            Some(p) if p.uid == uid => {
+                                info!(?uid, "resuming paused playback");
+                                resume(&file_player, &spotify_player, p.backend, &amp).await;
+                                if let Some(now_playing) = &mut now_playing {
+                                    now_playing.paused = false;
+                                }
+                                true
+                            }
+                            Some(_) => {
+                                info!(?uid, "different tag, discarding paused playback");
+                                record_position(
+                                    &mut positions,
+                                    &position_key,
+                                    backend,
+                                    &file_player,
+                                    &spotify_player,
+                                )
+                                .await;
+                                stop(&file_player, &spotify_player, &amp).await;
+                                now_playing = None;
+                                backend = None;
+                                position_key = None;
+                                false
+                            }
+                            None => false,
+                        };
+                        match responder.send(resumed) {
+                            Ok(_) => {}
+                            Err(_) => error!("error sending resume command response"),
+                        };
                     }
-                    PlayerRequestMessage::URL(url) => {
+                    PlayerRequestMessage::URL {
+                        url,
+                        title,
+                        uid,
+                        options,
+                    } => {
+                        if let Some(action) = Action::from_url(&url) {
+                            info!(?action, "received action");
+                            match action {
+                                Action::VolumeSet(volume) => {
+                                    set_volume_absolute(&mixer, volume).await;
+                                    file_player.volume_changed().await;
+                                }
+                                Action::SleepTimer(duration) => {
+                                    sleep_deadline = Some(Instant::now() + duration);
+                                }
+                                Action::ShuffleToggle => {
+                                    shuffle = !shuffle;
+                                    set_shuffle(&spotify_player, shuffle).await;
+                                }
+                                Action::Shutdown => {
+                                    record_position(
+                                        &mut positions,
+                                        &position_key,
+                                        backend,
+                                        &file_player,
+                                        &spotify_player,
+                                    )
+                                    .await;
+                                    save_positions(&mut positions).await;
+                                    match system_shutdown::shutdown() {
+                                        Ok(_) => info!("shutting down"),
+                                        Err(e) => error!(%e, "Error shutting down!"),
+                                    };
+                                }
+                                Action::StopAll => {
+                                    record_position(
+                                        &mut positions,
+                                        &position_key,
+                                        backend,
+                                        &file_player,
+                                        &spotify_player,
+                                    )
+                                    .await;
+                                    stop(&file_player, &spotify_player, &amp).await;
+                                    now_playing = None;
+                                    backend = None;
+                                    paused = None;
+                                    position_key = None;
+                                    sleep_deadline = None;
+                                }
+                                Action::NextTrack => match backend {
+                                    Some(Backend::Spotify) => next_track(&spotify_player).await,
+                                    _ => info!("no spotify playback, nothing to skip"),
+                                },
+                            }
+                            continue;
+                        }
+
                         let log_url = url.to_string();
-                        info!(log_url, "received URL player request");
+                        info!(log_url, ?title, ?options, "received URL player request");
+                        let playing = NowPlaying {
+                            url: log_url.clone(),
+                            title,
+                            paused: false,
+                        };
+                        paused = None;
+
+                        // remember where the previous playback got to before replacing it
+                        record_position(
+                            &mut positions,
+                            &position_key,
+                            backend,
+                            &file_player,
+                            &spotify_player,
+                        )
+                        .await;
+                        let key = match uid {
+                            Some(uid) => hex::encode(uid),
+                            None => log_url.clone(),
+                        };
+                        let saved = match options.resume {
+                            true => positions.get(&key),
+                            false => None,
+                        };
+                        let start = match saved {
+                            Some(start) => {
+                                info!(
+                                    key,
+                                    start.track, start.offset_ms, "resuming from saved position"
+                                );
+                                start
+                            }
+                            None => Position {
+                                track: 0,
+                                offset_ms: options
+                                    .start_offset
+                                    .map(|offset| offset.as_millis() as u64)
+                                    .unwrap_or(0),
+                            },
+                        };
+                        // cards that always start over don't need their progress remembered
+                        let key = options.resume.then_some(key);
+                        position_key = None;
+
+                        if let Some(volume) = options.volume {
+                            set_volume_absolute(&mixer, volume).await;
+                            file_player.volume_changed().await;
+                        }
 
                         match url.scheme() {
                             "https" => match url.host_str() {
                                 Some("open.spotify.com") => {
                                     info!(log_url, "playing spotify from url");
-                                    play_spotify(&file_player, &mut spotify_player, url, &amp)
+                                    set_shuffle(&spotify_player, options.shuffle.unwrap_or(shuffle))
                                         .await;
+                                    set_repeat(&spotify_player, options.repeat).await;
+                                    play_spotify(
+                                        &file_player,
+                                        &mut spotify_player,
+                                        url,
+                                        start,
+                                        &amp,
+                                    )
+                                    .await;
+                                    now_playing = Some(playing);
+                                    backend = Some(Backend::Spotify);
+                                    position_key = key;
                                 }
                                 _ => error!(log_url, "unsupported URL"),
                             },
                             "file" => {
                                 // TODO: we should sanitize the path here...
                                 info!(log_url, "playing file from url");
-                                play_file(&file_player, &spotify_player, url, &amp).await;
+                                play_file(
+                                    &file_player,
+                                    &spotify_player,
+                                    url,
+                                    start,
+                                    options.repeat,
+                                    &amp,
+                                )
+                                .await;
+                                now_playing = Some(playing);
+                                backend = Some(Backend::File);
+                                position_key = key;
                             }
                             &_ => info!(log_url, "not sure what to do with this url"),
                         }
                     }
+                    PlayerRequestMessage::ResetPosition { key, responder } => {
+                        info!(key, "received reset position request");
+                        // stop tracking the current playback too, or the next save brings it back
+                        if position_key.as_ref() == Some(&key) {
+                            position_key = None;
+                        }
+                        let removed = positions.remove(&key);
+                        save_positions(&mut positions).await;
+                        match responder.send(removed) {
+                            Ok(_) => {}
+                            Err(_) => error!("error sending reset position command response"),
+                        };
+                    }
+                    PlayerRequestMessage::Shutdown { responder } => {
+                        info!("received shutdown request, saving positions");
+                        record_position(
+                            &mut positions,
+                            &position_key,
+                            backend,
+                            &file_player,
+                            &spotify_player,
+                        )
+                        .await;
+                        save_positions(&mut positions).await;
+                        match responder.send(()) {
+                            Ok(_) => {}
+                            Err(_) => error!("error sending shutdown command response"),
+                        };
+                    }
+                    PlayerRequestMessage::Status { responder } => {
+                        match responder.send(now_playing.clone()) {
+                            Ok(_) => {}
+                            Err(_) => error!("error sending status command response"),
+                        };
+                    }
                     PlayerRequestMessage::VolumeUp { responder } => {
                         let new_volume = set_volume_delta(&mixer, 0.01).await;
                         file_player.volume_changed().await;
@@ -161,10 +513,118 @@ async fn stop(file_player: &FilePlayer, spotify_player: &SpotifyPlayer, amp: &Am
     };
 }
 
+/// Remembers where the current playback is, or forgets it once playback has finished.
+async fn record_position(
+    positions: &mut ResumePositions,
+    key: &Option<String>,
+    backend: Option<Backend>,
+    file_player: &FilePlayer,
+    spotify_player: &SpotifyPlayer,
+) {
+    let (Some(key), Some(backend)) = (key, backend) else {
+        return;
+    };
+    let position = match backend {
+        Backend::Spotify => spotify_player.position().await,
+        Backend::File => file_player.position().await,
+    };
+    match position {
+        Some(position) => positions.set(key, position),
+        None => {
+            positions.remove(key);
+        }
+    }
+}
+
+async fn save_positions(positions: &mut ResumePositions) {
+    match positions.save().await {
+        Ok(_) => {}
+        Err(e) => {
+            let error_msg = e.to_string();
+            error!(error_msg, "Error saving resume positions!")
+        }
+    }
+}
+
+async fn pause(
+    file_player: &FilePlayer,
+    spotify_player: &SpotifyPlayer,
+    backend: Backend,
+    amp: &Amp,
+) {
+    match backend {
+        Backend::Spotify => match spotify_player.pause().await {
+            Ok(_) => {}
+            Err(e) => error!(e, "Error pausing spotify playback!"),
+        },
+        Backend::File => match file_player.pause().await {
+            Ok(_) => {}
+            Err(e) => error!(e, "Error pausing file playback!"),
+        },
+    };
+    match amp.off().await {
+        Ok(_) => {}
+        Err(e) => {
+            let error_msg = e.to_string();
+            error!(error_msg, "Error switching off amp after pausing playback!")
+        }
+    };
+}
+
+async fn resume(
+    file_player: &FilePlayer,
+    spotify_player: &SpotifyPlayer,
+    backend: Backend,
+    amp: &Amp,
+) {
+    match amp.on().await {
+        Ok(_) => {}
+        Err(e) => {
+            let error_msg = e.to_string();
+            error!(
+                error_msg,
+                "Error switching on amp before resuming playback!"
+            )
+        }
+    };
+    match backend {
+        Backend::Spotify => match spotify_player.resume().await {
+            Ok(_) => {}
+            Err(e) => error!(e, "Error resuming spotify playback!"),
+        },
+        Backend::File => match file_player.resume().await {
+            Ok(_) => {}
+            Err(e) => error!(e, "Error resuming file playback!"),
+        },
+    };
+}
+
+async fn next_track(spotify_player: &SpotifyPlayer) {
+    match spotify_player.next().await {
+        Ok(_) => {}
+        Err(e) => error!(e, "Error skipping spotify track!"),
+    };
+}
+
+async fn set_repeat(spotify_player: &SpotifyPlayer, repeat: bool) {
+    match spotify_player.set_repeat(repeat).await {
+        Ok(_) => {}
+        Err(e) => error!(e, "Error setting spotify repeat!"),
+    };
+}
+
+async fn set_shuffle(spotify_player: &SpotifyPlayer, shuffle: bool) {
+    match spotify_player.set_shuffle(shuffle).await {
+        Ok(_) => {}
+        Err(e) => error!(e, "Error setting spotify shuffle!"),
+    };
+}
+
 async fn play_spotify(
     file_player: &FilePlayer,
     spotify_player: &mut SpotifyPlayer,
     url: Url,
+    start: Position,
     amp: &Amp,
 ) {
     match amp.on().await {
@@ -183,13 +643,20 @@ async fn play_spotify(
         Ok(_) => {}
         Err(e) => error!(e, "Error stopping file playback!"),
     };
-    match spotify_player.play_from_url(url).await {
+    match spotify_player.play_from_url(url, start).await {
         Ok(_) => {}
         Err(e) => error!(e, "Error playing spotify!"),
     };
 }
 
-async fn play_file(file_player: &FilePlayer, spotify_player: &SpotifyPlayer, url: Url, amp: &Amp) {
+async fn play_file(
+    file_player: &FilePlayer,
+    spotify_player: &SpotifyPlayer,
+    url: Url,
+    start: Position,
+    repeat: bool,
+    amp: &Amp,
+) {
     match amp.on().await {
         Ok(_) => {}
         Err(e) => {
@@ -211,7 +678,7 @@ async fn play_file(file_player: &FilePlayer, spotify_player: &SpotifyPlayer, url
     let file_path = url.path().trim_matches('/');
     let file_path = String::from_utf8(percent_decode_str(file_path).collect_vec()).expect("oof");
 
-    match file_player.play(file_path, true).await {
+    match file_player.play(file_path, true, start, repeat).await {
         Ok(_) => {}
         Err(e) => error!(e, "Error playing file!"),
     };
@@ -0,0 +1,334 @@
+use rppal::i2c::I2c;
+use rppal::spi::{reverse_bits, Spi};
+use std::time::{Duration, Instant};
+
+use crate::reader::{AtqA, Error, Reader, Uid};
+
+// Minimal PN532 driver. The PN532 runs the ISO/IEC 14443-3 activation itself, so REQA,
+// anticollision and select all happen in a single InListPassiveTarget, and frames to the
+// selected tag go through InCommunicateThru with the CRC handled by the chip.
+
+#[allow(dead_code)]
+mod command {
+    pub const GET_FIRMWARE_VERSION: u8 = 0x02;
+    pub const SAM_CONFIGURATION: u8 = 0x14;
+    pub const RF_CONFIGURATION: u8 = 0x32;
+    pub const IN_DATA_EXCHANGE: u8 = 0x40;
+    pub const IN_COMMUNICATE_THRU: u8 = 0x42;
+    pub const IN_LIST_PASSIVE_TARGET: u8 = 0x4a;
+    pub const IN_RELEASE: u8 = 0x52;
+}
+
+#[allow(dead_code)]
+mod frame {
+    pub const PREAMBLE: [u8; 3] = [0x00, 0x00, 0xff];
+    pub const ACK: [u8; 6] = [0x00, 0x00, 0xff, 0x00, 0xff, 0x00];
+    pub const POSTAMBLE: u8 = 0x00;
+    /// Frame identifier for host to PN532
+    pub const TFI_HOST: u8 = 0xd4;
+    /// Frame identifier for PN532 to host
+    pub const TFI_PN532: u8 = 0xd5;
+}
+
+#[allow(dead_code)]
+mod spi_op {
+    pub const DATA_WRITE: u8 = 0x01;
+    pub const STATUS_READ: u8 = 0x02;
+    pub const DATA_READ: u8 = 0x03;
+}
+
+const I2C_ADDRESS: u16 = 0x24;
+const READY: u8 = 0x01;
+
+/// IC field of GetFirmwareVersion
+const IC_PN532: u8 = 0x32;
+/// 106 kbps type A, the only baud rate NTAGs support
+const BRTY_106_TYPE_A: u8 = 0x00;
+const TARGET: u8 = 0x01;
+/// Large enough for a FAST_READ of 15 pages, plus the frame around it
+const READ_BUFFER_SIZE: usize = 96;
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub enum Interface {
+    Spi(Spi),
+    I2c(I2c),
+}
+
+impl Interface {
+    pub fn i2c(mut i2c: I2c) -> Result<Self, Error> {
+        i2c.set_slave_address(I2C_ADDRESS)?;
+        Ok(Interface::I2c(i2c))
+    }
+
+    /// The PN532 talks LSB first over SPI, which the Raspberry Pi can't do in hardware.
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self {
+            Interface::Spi(spi) => {
+                let mut tx = vec![spi_op::DATA_WRITE];
+                tx.extend(data);
+                reverse_bits(&mut tx);
+                spi.write(&tx)?;
+            }
+            Interface::I2c(i2c) => {
+                i2c.write(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_ready(&mut self) -> Result<bool, Error> {
+        let status = match self {
+            Interface::Spi(spi) => {
+                let mut tx = [spi_op::STATUS_READ, 0];
+                reverse_bits(&mut tx);
+                let mut rx = [0u8; 2];
+                spi.transfer(&mut rx, &tx)?;
+                reverse_bits(&mut rx);
+                rx[1]
+            }
+            Interface::I2c(i2c) => {
+                let mut rx = [0u8; 1];
+                i2c.read(&mut rx)?;
+                rx[0]
+            }
+        };
+        Ok(status & READY != 0)
+    }
+
+    fn wait_ready(&mut self) -> Result<(), Error> {
+        let start = Instant::now();
+        while !self.is_ready()? {
+            if start.elapsed() > RESPONSE_TIMEOUT {
+                return Err(Error::Timeout);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        match self {
+            Interface::Spi(spi) => {
+                let mut tx = vec![0u8; len + 1];
+                tx[0] = spi_op::DATA_READ;
+                reverse_bits(&mut tx);
+                let mut rx = vec![0u8; len + 1];
+                spi.transfer(&mut rx, &tx)?;
+                reverse_bits(&mut rx);
+                Ok(rx.split_off(1))
+            }
+            Interface::I2c(i2c) => {
+                // every I2C read starts with the status byte
+                let mut rx = vec![0u8; len + 1];
+                i2c.read(&mut rx)?;
+                Ok(rx.split_off(1))
+            }
+        }
+    }
+}
+
+pub struct Pn532 {
+    interface: Interface,
+    /// Activated by the last InListPassiveTarget, handed out by `select`
+    target: Option<Uid>,
+}
+
+impl Pn532 {
+    pub fn new(interface: Interface) -> Result<Self, Error> {
+        let mut pn532 = Self {
+            interface,
+            target: None,
+        };
+        pn532.init()?;
+        Ok(pn532)
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        // normal mode, no timeout, no IRQ
+        self.command(command::SAM_CONFIGURATION, &[0x01, 0x00, 0x00])?;
+        // try passive activation twice instead of forever, so an empty field answers quickly
+        self.command(command::RF_CONFIGURATION, &[0x05, 0xff, 0x01, 0x01])?;
+        Ok(())
+    }
+
+    /// The IC, version, revision and supported protocols, in that order.
+    pub fn firmware_version(&mut self) -> Result<[u8; 4], Error> {
+        match *self.command(command::GET_FIRMWARE_VERSION, &[])?.as_slice() {
+            [ic, version, revision, support] if ic == IC_PN532 => {
+                Ok([ic, version, revision, support])
+            }
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    fn list_passive_target(&mut self) -> Result<AtqA, Error> {
+        self.target = None;
+        let response = self.command(command::IN_LIST_PASSIVE_TARGET, &[1, BRTY_106_TYPE_A])?;
+
+        match response.as_slice() {
+            [0, ..] => Err(Error::Timeout),
+            [_, _, atqa_0, atqa_1, sak, uid_len, uid @ ..] if uid.len() >= *uid_len as usize => {
+                self.target = Some(Uid::new(uid[..*uid_len as usize].to_vec(), *sak));
+                Ok(AtqA::new([*atqa_0, *atqa_1]))
+            }
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    /// Sends a command and returns its response data, after the response code.
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut body = vec![frame::TFI_HOST, command];
+        body.extend(data);
+        let len = u8::try_from(body.len()).map_err(|_| Error::BufferOverflow)?;
+
+        let mut tx = frame::PREAMBLE.to_vec();
+        tx.push(len);
+        tx.push(len.wrapping_neg());
+        tx.extend(&body);
+        tx.push(checksum(&body));
+        tx.push(frame::POSTAMBLE);
+        self.interface.write(&tx)?;
+
+        self.interface.wait_ready()?;
+        if self.interface.read(frame::ACK.len())? != frame::ACK {
+            return Err(Error::InvalidFrame);
+        }
+
+        self.interface.wait_ready()?;
+        let rx = self.interface.read(READ_BUFFER_SIZE)?;
+        let body = parse_frame(&rx)?;
+        match body {
+            [frame::TFI_PN532, response, data @ ..] if *response == command + 1 => {
+                Ok(data.to_vec())
+            }
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+}
+
+/// The two's complement of the byte sum, so that everything adds up to zero.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+/// Finds the frame in what was read and returns its TFI and data after checking both checksums.
+fn parse_frame(rx: &[u8]) -> Result<&[u8], Error> {
+    let start = rx
+        .windows(2)
+        .position(|w| w == [0x00, 0xff])
+        .ok_or(Error::InvalidFrame)?
+        + 2;
+
+    match rx.get(start..) {
+        Some([len, lcs, rest @ ..]) if len.wrapping_add(*lcs) == 0 => {
+            let len = *len as usize;
+            match rest.get(..=len) {
+                Some([body @ .., dcs]) if checksum(body) == *dcs => Ok(body),
+                _ => Err(Error::InvalidFrame),
+            }
+        }
+        _ => Err(Error::InvalidFrame),
+    }
+}
+
+/// Maps the error code in the status byte of InCommunicateThru and InDataExchange.
+fn status_error(status: u8) -> Option<Error> {
+    match status & 0x3f {
+        0x00 => None,
+        0x01 | 0x0a => Some(Error::Timeout),
+        0x02 => Some(Error::Crc),
+        0x03 => Some(Error::Parity),
+        // a 4 bit NAK doesn't make up a byte, and a NAKed Ultralight command is reported
+        // like a failed MIFARE authentication
+        0x04 | 0x14 => Some(Error::Nak(0)),
+        0x06 => Some(Error::Collision),
+        0x07 | 0x09 | 0x0e => Some(Error::BufferOverflow),
+        0x0d => Some(Error::Overheating),
+        _ => Some(Error::Protocol),
+    }
+}
+
+impl Reader for Pn532 {
+    fn reqa(&mut self) -> Result<AtqA, Error> {
+        self.list_passive_target()
+    }
+
+    fn wupa(&mut self) -> Result<AtqA, Error> {
+        self.list_passive_target()
+    }
+
+    fn hlta(&mut self) -> Result<(), Error> {
+        self.target = None;
+        match *self.command(command::IN_RELEASE, &[TARGET])?.as_slice() {
+            [status] => match status_error(status) {
+                None => Ok(()),
+                Some(e) => Err(e),
+            },
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    fn select(&mut self, _atqa: &AtqA) -> Result<Uid, Error> {
+        self.target.take().ok_or(Error::Timeout)
+    }
+
+    fn health_check(&mut self) -> Result<(), Error> {
+        self.firmware_version().map(|_| ())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        // the RSTPDN pin isn't wired up on the common breakouts, so all we can do is reconfigure
+        self.target = None;
+        self.init()?;
+        self.health_check()
+    }
+
+    fn transceive_crc(&mut self, tx: &[u8]) -> Result<Vec<u8>, Error> {
+        let response = self.command(command::IN_COMMUNICATE_THRU, tx)?;
+        match response.split_first() {
+            Some((status, data)) => match status_error(*status) {
+                None => Ok(data.to_vec()),
+                Some(e) => Err(e),
+            },
+            None => Err(Error::InvalidFrame),
+        }
+    }
+
+    fn transceive_ack(&mut self, tx: &[u8]) -> Result<(), Error> {
+        // InDataExchange deals with the ACK of Ultralight style writes itself
+        let mut data = vec![TARGET];
+        data.extend(tx);
+        match self.command(command::IN_DATA_EXCHANGE, &data)?.first() {
+            Some(status) => match status_error(*status) {
+                None => Ok(()),
+                Some(e) => Err(e),
+            },
+            None => Err(Error::InvalidFrame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pn532::*;
+
+    #[test]
+    fn parse_response_frame() {
+        // GetFirmwareVersion response, with the leading zeros the PN532 tends to send
+        let rx = [
+            0x00, 0x00, 0x00, 0xff, 0x06, 0xfa, 0xd5, 0x03, 0x32, 0x01, 0x06, 0x07, 0xe8, 0x00,
+            0x00, 0x00,
+        ];
+        assert_eq!(
+            parse_frame(&rx).unwrap(),
+            &[0xd5, 0x03, 0x32, 0x01, 0x06, 0x07]
+        );
+
+        let mut corrupted = rx;
+        corrupted[9] = 0x02;
+        assert!(matches!(parse_frame(&corrupted), Err(Error::InvalidFrame)));
+        assert!(matches!(parse_frame(&rx[..10]), Err(Error::InvalidFrame)));
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::json_store;
+
+/// Where playback of a URL got to: the index into its track list, in album or playlist order,
+/// and the offset into that track.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub track: usize,
+    pub offset_ms: u64,
+}
+
+/// Resume positions keyed by tag UID, or by URL for playback that didn't come from a tag.
+/// Changes are only kept in memory until `save` is called.
+pub struct ResumePositions {
+    path: PathBuf,
+    positions: BTreeMap<String, Position>,
+    dirty: bool,
+}
+
+impl ResumePositions {
+    const FILE_NAME: &'static str = "resume_positions.json";
+
+    pub async fn load() -> Self {
+        let path = json_store::cache_path(Self::FILE_NAME);
+        let positions: BTreeMap<String, Position> = json_store::load(&path).await;
+        info!(count = positions.len(), "loaded resume positions");
+
+        ResumePositions {
+            path,
+            positions,
+            dirty: false,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Position> {
+        self.positions.get(key).copied()
+    }
+
+    pub fn set(&mut self, key: &str, position: Position) {
+        if self.positions.get(key) != Some(&position) {
+            self.positions.insert(String::from(key), position);
+            self.dirty = true;
+        }
+    }
+
+    /// Returns whether there was a position for the key.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let removed = self.positions.remove(key).is_some();
+        self.dirty |= removed;
+        removed
+    }
+
+    /// Writes the positions to disk if anything changed since the last save.
+    pub async fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        json_store::save(&self.path, &self.positions).await?;
+        self.dirty = false;
+        Ok(())
+    }
+}
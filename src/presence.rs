@@ -0,0 +1,137 @@
+use std::env;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PresenceEvent {
+    Added(Vec<u8>),
+    Removed(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Absent { candidate: Option<(Vec<u8>, usize)> },
+    Present { uid: Vec<u8>, misses: usize },
+}
+
+/// Debounces the raw poll results, so a single missed REQA or a wobbly card doesn't show up
+/// as the tag being removed and added again.
+#[derive(Debug)]
+pub struct Presence {
+    state: State,
+    confirmation_reads: usize,
+    removal_misses: usize,
+}
+
+impl Presence {
+    pub fn new(confirmation_reads: usize, removal_misses: usize) -> Self {
+        Self {
+            state: State::Absent { candidate: None },
+            confirmation_reads: confirmation_reads.max(1),
+            removal_misses: removal_misses.max(1),
+        }
+    }
+
+    /// Reads `TAG_CONFIRMATION_READS` and `TAG_REMOVAL_MISSES`, counted in poll intervals.
+    pub fn from_env() -> Self {
+        let confirmation_reads = env::var("TAG_CONFIRMATION_READS")
+            .ok()
+            .and_then(|reads| reads.parse().ok())
+            .unwrap_or(2);
+        let removal_misses = env::var("TAG_REMOVAL_MISSES")
+            .ok()
+            .and_then(|misses| misses.parse().ok())
+            .unwrap_or(3);
+        Self::new(confirmation_reads, removal_misses)
+    }
+
+    /// Feeds one poll result, returning the events it causes. Swapping one tag for another
+    /// between two polls removes the old one before the new one gets added.
+    pub fn update(&mut self, reading: Option<&[u8]>) -> Vec<PresenceEvent> {
+        let mut events = Vec::new();
+
+        if let State::Present { uid, misses } = &mut self.state {
+            match reading {
+                Some(reading) if reading == uid.as_slice() => *misses = 0,
+                Some(_) => {
+                    events.push(PresenceEvent::Removed(uid.clone()));
+                    self.state = State::Absent { candidate: None };
+                }
+                None => {
+                    *misses += 1;
+                    if *misses >= self.removal_misses {
+                        events.push(PresenceEvent::Removed(uid.clone()));
+                        self.state = State::Absent { candidate: None };
+                    }
+                }
+            }
+        }
+
+        if let State::Absent { candidate } = &mut self.state {
+            *candidate = match (reading, candidate.take()) {
+                (Some(reading), Some((uid, reads))) if *reading == uid => Some((uid, reads + 1)),
+                (Some(reading), _) => Some((reading.to_vec(), 1)),
+                (None, _) => None,
+            };
+
+            if let Some((uid, reads)) = candidate {
+                if *reads >= self.confirmation_reads {
+                    let uid = uid.clone();
+                    events.push(PresenceEvent::Added(uid.clone()));
+                    self.state = State::Present { uid, misses: 0 };
+                }
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::presence::*;
+
+    const A: [u8; 7] = [0x04, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+    const B: [u8; 4] = [0x11, 0x12, 0x13, 0x14];
+
+    #[test]
+    fn debounce_presence() {
+        let mut presence = Presence::new(2, 3);
+
+        // a single read is not enough, and a miss resets the confirmation
+        assert_eq!(presence.update(Some(&A)), vec![]);
+        assert_eq!(presence.update(None), vec![]);
+        assert_eq!(presence.update(Some(&A)), vec![]);
+        assert_eq!(
+            presence.update(Some(&A)),
+            vec![PresenceEvent::Added(A.to_vec())]
+        );
+
+        // missed polls within the grace period are ignored
+        assert_eq!(presence.update(None), vec![]);
+        assert_eq!(presence.update(None), vec![]);
+        assert_eq!(presence.update(Some(&A)), vec![]);
+        assert_eq!(presence.update(None), vec![]);
+        assert_eq!(presence.update(None), vec![]);
+        assert_eq!(
+            presence.update(None),
+            vec![PresenceEvent::Removed(A.to_vec())]
+        );
+        assert_eq!(presence.update(None), vec![]);
+    }
+
+    #[test]
+    fn swap_tags() {
+        let mut presence = Presence::new(1, 3);
+
+        assert_eq!(
+            presence.update(Some(&A)),
+            vec![PresenceEvent::Added(A.to_vec())]
+        );
+        assert_eq!(
+            presence.update(Some(&B)),
+            vec![
+                PresenceEvent::Removed(A.to_vec()),
+                PresenceEvent::Added(B.to_vec())
+            ]
+        );
+    }
+}
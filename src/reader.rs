@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::fmt;
+
+// The ISO/IEC 14443-3 type A operations the NTAG logic needs from a reader, so it can run
+// against the MFRC522 as well as against a simulated tag.
+
+#[derive(Debug)]
+pub enum Error {
+    Spi(rppal::spi::Error),
+    I2c(rppal::i2c::Error),
+    /// The reader sent a malformed frame or didn't acknowledge a command
+    InvalidFrame,
+    BufferOverflow,
+    Collision,
+    Crc,
+    IncompleteFrame,
+    /// The PICC answered with a NAK instead of an ACK, carrying the 4 bit NAK code
+    Nak(u8),
+    Overheating,
+    Parity,
+    Protocol,
+    Proprietary,
+    Timeout,
+    /// The reader itself stopped answering, as opposed to a PICC not answering
+    Unresponsive,
+    /// The reader reported a version we don't know, or garbage
+    UnknownVersion(u8),
+    Unsupported,
+    Wr,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Spi(e) => write!(f, "SPI error: {e}"),
+            Error::I2c(e) => write!(f, "I2C error: {e}"),
+            Error::UnknownVersion(version) => write!(f, "unknown reader version {version:#x}"),
+            Error::Nak(code) => write!(f, "PICC answered with NAK {code:#x}"),
+            e => write!(f, "{e:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether the reader itself is in trouble, rather than the PICC or the RF field.
+    pub fn is_reader_fault(&self) -> bool {
+        matches!(
+            self,
+            Error::Spi(_)
+                | Error::I2c(_)
+                | Error::InvalidFrame
+                | Error::Unresponsive
+                | Error::UnknownVersion(_)
+        )
+    }
+}
+
+impl From<rppal::spi::Error> for Error {
+    fn from(e: rppal::spi::Error) -> Self {
+        Error::Spi(e)
+    }
+}
+
+impl From<rppal::i2c::Error> for Error {
+    fn from(e: rppal::i2c::Error) -> Self {
+        Error::I2c(e)
+    }
+}
+
+/// Answer To reQuest type A
+pub struct AtqA {
+    bytes: [u8; 2],
+}
+
+impl AtqA {
+    pub fn new(bytes: [u8; 2]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 2] {
+        &self.bytes
+    }
+}
+
+pub struct Uid {
+    bytes: Vec<u8>,
+    sak: u8,
+}
+
+impl Uid {
+    pub fn new(bytes: Vec<u8>, sak: u8) -> Self {
+        Self { bytes, sak }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn sak(&self) -> u8 {
+        self.sak
+    }
+}
+
+pub trait Reader {
+    /// Wakes up PICCs in the IDLE state.
+    fn reqa(&mut self) -> Result<AtqA, Error>;
+
+    /// Wakes up PICCs in the IDLE and HALT states.
+    fn wupa(&mut self) -> Result<AtqA, Error>;
+
+    /// Sends the PICC back to the HALT state.
+    fn hlta(&mut self) -> Result<(), Error>;
+
+    /// Runs anticollision and selection through all cascade levels.
+    fn select(&mut self, atqa: &AtqA) -> Result<Uid, Error>;
+
+    /// Sends `tx` with a CRC appended, and returns the response after checking and stripping
+    /// its CRC. A 4 bit response is reported as `Error::Nak`.
+    fn transceive_crc(&mut self, tx: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Sends `tx` with a CRC appended and expects a 4 bit ACK in return.
+    fn transceive_ack(&mut self, tx: &[u8]) -> Result<(), Error>;
+
+    /// Checks that the reader itself still answers sensibly.
+    fn health_check(&mut self) -> Result<(), Error>;
+
+    /// Resets and reinitialises the reader after it got into a bad state.
+    fn reset(&mut self) -> Result<(), Error>;
+
+    /// Sends a REQA without waiting for the answer, for readers that signal a card answering
+    /// through their IRQ pin.
+    fn arm_card_detect(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReaderState {
+    /// The reader hasn't come up yet, e.g. because it isn't connected
+    Missing,
+    Ok,
+    Recovering,
+    Failed,
+}
+
+/// How the reader is doing, as reported over HTTP.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReaderHealth {
+    pub state: ReaderState,
+    pub consecutive_errors: usize,
+    pub recoveries: usize,
+    pub last_error: Option<String>,
+}
+
+impl Default for ReaderHealth {
+    fn default() -> Self {
+        Self {
+            state: ReaderState::Missing,
+            consecutive_errors: 0,
+            recoveries: 0,
+            last_error: None,
+        }
+    }
+}
@@ -1,20 +1,48 @@
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::{debug_handler, extract::Query, extract::State, routing::post, Json, Router};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{
+    debug_handler,
+    extract::Query,
+    extract::State,
+    routing::{delete, get, post},
+    Json, Router,
+};
 use serde::{Deserialize, Serialize};
 use std::env;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 use tokio::task::JoinSet;
-use tracing::{error, info};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info, warn};
 use url::Url;
 
 use crate::amp::Amp;
 use crate::led::Led;
-use crate::player::PlayerRequestMessage;
+use crate::ndef::{Message, Record};
+use crate::ntag::TagRequestMessage;
+use crate::ntag21x::{
+    Originality, Password, PasswordConfig, ReadError, TagDump, TagInfo, TagType, WriteError,
+    WriteSummary,
+};
+use crate::playback_options::PlaybackOptions;
+use crate::player::{NowPlaying, PlayerRequestMessage};
+use crate::reader::{ReaderHealth, ReaderState, Uid};
+use crate::tag_events::TagEvent;
+use crate::tag_mappings::{TagMapping, TagMappings};
+
+/// First page of user memory, so the NDEF message and configuration need the password
+const DEFAULT_AUTH0: u8 = 4;
 
 #[derive(Clone)]
 pub struct AppState {
     pub sender: mpsc::Sender<PlayerRequestMessage>,
+    pub tag_sender: mpsc::Sender<TagRequestMessage>,
+    pub reader_health: watch::Receiver<ReaderHealth>,
+    /// The tag currently on the reader
+    pub tag_info: watch::Receiver<Option<TagInfo>>,
+    pub tag_events: broadcast::Sender<TagEvent>,
+    pub mappings: TagMappings,
     pub amp: Amp,
     pub led: Led,
 }
@@ -34,6 +62,7 @@ async fn start_server(app_state: AppState) -> Result<(), Box<dyn std::error::Err
     let app = Router::new()
         .route("/url", post(url))
         .route("/stop", post(stop))
+        .route("/status", get(status))
         .route("/volume/up", post(volume_up))
         .route("/volume/down", post(volume_down))
         .route("/volume/set", post(volume_set))
@@ -43,6 +72,23 @@ async fn start_server(app_state: AppState) -> Result<(), Box<dyn std::error::Err
         .route("/amp/power-off", post(amp_power_off))
         .route("/led/led-on", post(led_on))
         .route("/led/led-off", post(led_off))
+        .route("/tag/write", post(tag_write))
+        .route(
+            "/tag/password",
+            post(tag_password_set).delete(tag_password_remove),
+        )
+        .route("/tag/lock", post(tag_lock))
+        .route("/tag", get(tag_dump))
+        .route("/tag/info", get(tag_info))
+        .route("/tag/events", get(tag_events))
+        .route("/reader", get(reader))
+        .route("/positions", delete(positions_reset))
+        .route(
+            "/mappings",
+            get(mappings_list)
+                .post(mappings_set)
+                .delete(mappings_delete),
+        )
         .with_state(app_state);
 
     let bind_address: std::net::SocketAddr = env::var("BIND_ADDRESS")
@@ -59,17 +105,28 @@ async fn start_server(app_state: AppState) -> Result<(), Box<dyn std::error::Err
 #[derive(Deserialize)]
 struct SpotifyQuery {
     url: String,
+    title: Option<String>,
 }
 
 #[debug_handler]
 async fn url(State(state): State<AppState>, spotify_query: Query<SpotifyQuery>) {
     let spotify_query: SpotifyQuery = spotify_query.0;
     let url = spotify_query.url;
+    let title = spotify_query.title;
 
-    info!(url, "Got URL request");
+    info!(url, ?title, "Got URL request");
     let url = Url::parse(&url).expect("couldn't parse this");
 
-    match state.sender.send(PlayerRequestMessage::URL(url)).await {
+    match state
+        .sender
+        .send(PlayerRequestMessage::URL {
+            url,
+            title,
+            uid: None,
+            options: PlaybackOptions::default(),
+        })
+        .await
+    {
         Ok(_) => info!("submitted URL request"),
         Err(e) => error!("error submitting URL request: {e}"),
     };
@@ -85,6 +142,47 @@ async fn stop(State(state): State<AppState>) {
     };
 }
 
+#[derive(Serialize)]
+struct Status {
+    now_playing: Option<NowPlaying>,
+}
+
+#[debug_handler]
+async fn status(State(state): State<AppState>) -> impl IntoResponse {
+    info!("Got status request");
+
+    let (sender, receiver) = oneshot::channel::<Option<NowPlaying>>();
+
+    match state
+        .sender
+        .send(PlayerRequestMessage::Status { responder: sender })
+        .await
+    {
+        Ok(_) => info!("submitted status request"),
+        Err(e) => error!("error submitting status request: {e}"),
+    };
+
+    match receiver.await {
+        Ok(now_playing) => (StatusCode::OK, Json(Status { now_playing })).into_response(),
+        Err(_) => {
+            error!("didn't receive player command response");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("error receiving player command response"),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[debug_handler]
+async fn reader(State(state): State<AppState>) -> impl IntoResponse {
+    info!("Got reader health request");
+
+    let health = state.reader_health.borrow().clone();
+    (StatusCode::OK, Json(health)).into_response()
+}
+
 #[derive(Serialize)]
 struct Volume {
     volume: f64,
@@ -288,3 +386,491 @@ async fn led_off(State(state): State<AppState>) -> impl IntoResponse {
         }
     }
 }
+
+#[debug_handler]
+async fn tag_dump(State(state): State<AppState>) -> impl IntoResponse {
+    info!("Got tag dump request");
+
+    let (sender, receiver) = oneshot::channel::<Result<TagDump, ReadError>>();
+    let request = TagRequestMessage::Dump { responder: sender };
+    match submit_tag_request(&state, request, receiver).await {
+        Ok(dump) => (StatusCode::OK, Json(dump)).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Streams tag events as server-sent events until the client goes away.
+#[debug_handler]
+async fn tag_events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    info!("Got tag events request");
+
+    let events =
+        BroadcastStream::new(state.tag_events.subscribe()).filter_map(|event| match event {
+            Ok(event) => Some(Event::default().json_data(event)),
+            Err(e) => {
+                warn!(%e, "tag events subscriber fell behind");
+                None
+            }
+        });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Serialize)]
+struct TagInfoResponse {
+    uid: String,
+    tag_type: TagType,
+    originality: Option<Originality>,
+}
+
+#[debug_handler]
+async fn tag_info(State(state): State<AppState>) -> impl IntoResponse {
+    info!("Got tag info request");
+
+    let tag_info = state.tag_info.borrow().clone();
+    match tag_info {
+        Some(tag_info) => (
+            StatusCode::OK,
+            Json(TagInfoResponse {
+                uid: hex::encode(tag_info.uid),
+                tag_type: tag_info.tag_type,
+                originality: tag_info.originality,
+            }),
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, Json("no tag present")).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TagWriteQuery {
+    url: String,
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TagWritten {
+    uid: String,
+    tag_type: TagType,
+    bytes: usize,
+}
+
+#[debug_handler]
+async fn tag_write(
+    State(state): State<AppState>,
+    tag_write_query: Query<TagWriteQuery>,
+) -> impl IntoResponse {
+    let TagWriteQuery { url, password } = tag_write_query.0;
+    info!(url, "Got tag write request");
+
+    let password = match parse_optional_password(password) {
+        Ok(password) => password,
+        Err(response) => return response.into_response(),
+    };
+
+    if let Err(e) = Url::parse(&url) {
+        let e = e.to_string();
+        error!(e, "invalid URL in tag write request");
+        return (StatusCode::BAD_REQUEST, Json(format!("invalid URL: {e}"))).into_response();
+    }
+
+    let message = match Message::new(vec![Record::URI { uri: url }]) {
+        Some(message) => message,
+        None => {
+            return (StatusCode::PAYLOAD_TOO_LARGE, Json("URL too long")).into_response();
+        }
+    };
+
+    let (sender, receiver) = oneshot::channel::<Result<WriteSummary, WriteError>>();
+    let request = TagRequestMessage::Write {
+        message,
+        password,
+        responder: sender,
+    };
+    match submit_tag_request(&state, request, receiver).await {
+        Ok(summary) => (
+            StatusCode::OK,
+            Json(TagWritten {
+                uid: hex::encode(summary.uid),
+                tag_type: summary.tag_type,
+                bytes: summary.bytes,
+            }),
+        )
+            .into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Hands a request to the reader task and waits for the tag to be dealt with.
+async fn submit_tag_request<T, E>(
+    state: &AppState,
+    request: TagRequestMessage,
+    receiver: oneshot::Receiver<Result<T, E>>,
+) -> Result<T, Response>
+where
+    WriteError: From<E>,
+{
+    // nobody picks up tag requests until the reader is up
+    if state.reader_health.borrow().state == ReaderState::Missing {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json("tag reader not available"),
+        )
+            .into_response());
+    }
+
+    match state.tag_sender.send(request).await {
+        Ok(_) => info!("submitted tag request"),
+        Err(e) => {
+            error!("error submitting tag request: {e}");
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json("tag reader not available"),
+            )
+                .into_response());
+        }
+    };
+
+    match receiver.await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => {
+            let e = WriteError::from(e);
+            let status = match e {
+                WriteError::NoTag => StatusCode::NOT_FOUND,
+                WriteError::UnsupportedTag => StatusCode::UNPROCESSABLE_ENTITY,
+                WriteError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                WriteError::Locked | WriteError::ConfigurationLocked => StatusCode::LOCKED,
+                WriteError::AuthenticationFailed => StatusCode::FORBIDDEN,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err((status, Json(e.to_string())).into_response())
+        }
+        Err(_) => {
+            error!("didn't receive tag response");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("error receiving tag response"),
+            )
+                .into_response())
+        }
+    }
+}
+
+/// NTAG passwords are 4 bytes.
+fn parse_password(password: &str) -> Option<Password> {
+    hex::decode(password).ok()?.try_into().ok()
+}
+
+fn parse_optional_password(
+    password: Option<String>,
+) -> Result<Option<Password>, (StatusCode, Json<&'static str>)> {
+    match password.as_deref().map(parse_password) {
+        None => Ok(None),
+        Some(Some(password)) => Ok(Some(password)),
+        Some(None) => Err((
+            StatusCode::BAD_REQUEST,
+            Json("password needs to be 8 hex digits"),
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct TagUid {
+    uid: String,
+}
+
+impl TagUid {
+    fn new(uid: Uid) -> Self {
+        Self {
+            uid: hex::encode(uid.as_bytes()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TagPasswordSetQuery {
+    password: String,
+    /// Hex encoded, returned by the tag when the password is right
+    pack: Option<String>,
+    /// First protected page, defaults to the start of user memory
+    auth0: Option<u8>,
+    protect_reads: Option<bool>,
+    /// The password the tag is protected with so far
+    current: Option<String>,
+}
+
+#[debug_handler]
+async fn tag_password_set(
+    State(state): State<AppState>,
+    tag_password_set_query: Query<TagPasswordSetQuery>,
+) -> impl IntoResponse {
+    let query = tag_password_set_query.0;
+    info!(auth0 = query.auth0, "Got tag password set request");
+
+    let password = match parse_password(&query.password) {
+        Some(password) => password,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json("password needs to be 8 hex digits"),
+            )
+                .into_response();
+        }
+    };
+    let current = match parse_optional_password(query.current) {
+        Ok(current) => current,
+        Err(response) => return response.into_response(),
+    };
+    let pack = match query.pack.as_deref().map(hex::decode) {
+        None => [0; 2],
+        Some(Ok(pack)) if pack.len() == 2 => [pack[0], pack[1]],
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json("pack needs to be 4 hex digits"),
+            )
+                .into_response();
+        }
+    };
+
+    let config = PasswordConfig {
+        password,
+        pack,
+        auth0: query.auth0.unwrap_or(DEFAULT_AUTH0),
+        protect_reads: query.protect_reads.unwrap_or(false),
+    };
+    let (sender, receiver) = oneshot::channel::<Result<Uid, WriteError>>();
+    let request = TagRequestMessage::SetPassword {
+        config,
+        current,
+        responder: sender,
+    };
+    match submit_tag_request(&state, request, receiver).await {
+        Ok(uid) => (StatusCode::OK, Json(TagUid::new(uid))).into_response(),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+struct TagPasswordRemoveQuery {
+    password: String,
+}
+
+#[debug_handler]
+async fn tag_password_remove(
+    State(state): State<AppState>,
+    tag_password_remove_query: Query<TagPasswordRemoveQuery>,
+) -> impl IntoResponse {
+    info!("Got tag password remove request");
+
+    let password = match parse_password(&tag_password_remove_query.0.password) {
+        Some(password) => password,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json("password needs to be 8 hex digits"),
+            )
+                .into_response();
+        }
+    };
+
+    let (sender, receiver) = oneshot::channel::<Result<Uid, WriteError>>();
+    let request = TagRequestMessage::RemovePassword {
+        password,
+        responder: sender,
+    };
+    match submit_tag_request(&state, request, receiver).await {
+        Ok(uid) => (StatusCode::OK, Json(TagUid::new(uid))).into_response(),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+struct TagLockQuery {
+    password: Option<String>,
+}
+
+#[debug_handler]
+async fn tag_lock(
+    State(state): State<AppState>,
+    tag_lock_query: Query<TagLockQuery>,
+) -> impl IntoResponse {
+    info!("Got tag lock request");
+
+    let password = match parse_optional_password(tag_lock_query.0.password) {
+        Ok(password) => password,
+        Err(response) => return response.into_response(),
+    };
+
+    let (sender, receiver) = oneshot::channel::<Result<Uid, WriteError>>();
+    let request = TagRequestMessage::Lock {
+        password,
+        responder: sender,
+    };
+    match submit_tag_request(&state, request, receiver).await {
+        Ok(uid) => (StatusCode::OK, Json(TagUid::new(uid))).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// UIDs are 4, 7 or 10 bytes depending on the cascade level.
+fn parse_uid(uid: &str) -> Option<Vec<u8>> {
+    match hex::decode(uid) {
+        Ok(uid) if matches!(uid.len(), 4 | 7 | 10) => Some(uid),
+        _ => None,
+    }
+}
+
+#[debug_handler]
+async fn mappings_list(State(state): State<AppState>) -> impl IntoResponse {
+    info!("Got mappings list request");
+
+    (StatusCode::OK, Json(state.mappings.all().await)).into_response()
+}
+
+#[derive(Deserialize)]
+struct MappingSetQuery {
+    uid: String,
+    url: String,
+    title: Option<String>,
+}
+
+#[debug_handler]
+async fn mappings_set(
+    State(state): State<AppState>,
+    mapping_set_query: Query<MappingSetQuery>,
+) -> impl IntoResponse {
+    let MappingSetQuery { uid, url, title } = mapping_set_query.0;
+    info!(uid, url, ?title, "Got mapping set request");
+
+    let uid = match parse_uid(&uid) {
+        Some(uid) => uid,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json("invalid UID, expected 4, 7 or 10 bytes in hex"),
+            )
+                .into_response()
+        }
+    };
+
+    if let Err(e) = Url::parse(&url) {
+        let e = e.to_string();
+        error!(e, "invalid URL in mapping set request");
+        return (StatusCode::BAD_REQUEST, Json(format!("invalid URL: {e}"))).into_response();
+    }
+
+    match state.mappings.set(&uid, TagMapping { url, title }).await {
+        Ok(_) => (StatusCode::OK).into_response(),
+        Err(e) => {
+            error!("error saving tag mappings: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("error saving tag mappings"),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MappingDeleteQuery {
+    uid: String,
+}
+
+#[debug_handler]
+async fn mappings_delete(
+    State(state): State<AppState>,
+    mapping_delete_query: Query<MappingDeleteQuery>,
+) -> impl IntoResponse {
+    let uid = mapping_delete_query.0.uid;
+    info!(uid, "Got mapping delete request");
+
+    let uid = match parse_uid(&uid) {
+        Some(uid) => uid,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json("invalid UID, expected 4, 7 or 10 bytes in hex"),
+            )
+                .into_response()
+        }
+    };
+
+    match state.mappings.remove(&uid).await {
+        Ok(true) => (StatusCode::OK).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, Json("no mapping for UID")).into_response(),
+        Err(e) => {
+            error!("error saving tag mappings: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("error saving tag mappings"),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PositionResetQuery {
+    uid: Option<String>,
+    url: Option<String>,
+}
+
+#[debug_handler]
+async fn positions_reset(
+    State(state): State<AppState>,
+    position_reset_query: Query<PositionResetQuery>,
+) -> impl IntoResponse {
+    let PositionResetQuery { uid, url } = position_reset_query.0;
+    info!(?uid, ?url, "Got position reset request");
+
+    // positions are keyed like the player does it, by hex UID for tags, by URL otherwise
+    let key = match (uid, url) {
+        (Some(uid), None) => match parse_uid(&uid) {
+            Some(uid) => hex::encode(uid),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json("invalid UID, expected 4, 7 or 10 bytes in hex"),
+                )
+                    .into_response()
+            }
+        },
+        (None, Some(url)) => match Url::parse(&url) {
+            Ok(url) => url.to_string(),
+            Err(e) => {
+                return (StatusCode::BAD_REQUEST, Json(format!("invalid URL: {e}"))).into_response()
+            }
+        },
+        _ => return (StatusCode::BAD_REQUEST, Json("expected either uid or url")).into_response(),
+    };
+
+    let (sender, receiver) = oneshot::channel::<bool>();
+
+    match state
+        .sender
+        .send(PlayerRequestMessage::ResetPosition {
+            key,
+            responder: sender,
+        })
+        .await
+    {
+        Ok(_) => info!("submitted reset position request"),
+        Err(e) => error!("error submitting reset position request: {e}"),
+    };
+
+    match receiver.await {
+        Ok(true) => (StatusCode::OK).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, Json("no saved position")).into_response(),
+        Err(_) => {
+            error!("didn't receive player command response");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("error receiving player command response"),
+            )
+                .into_response()
+        }
+    }
+}
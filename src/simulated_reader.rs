@@ -0,0 +1,330 @@
+use crate::ntag21x::TagDump;
+use crate::reader::{AtqA, Error, Reader, Uid};
+
+// A reader with an NTAG21x in its field, backed by a page dump, so the tag logic can be
+// exercised without any hardware.
+
+#[allow(dead_code)]
+mod command {
+    pub const GET_VERSION: u8 = 0x60;
+    pub const READ: u8 = 0x30;
+    pub const FAST_READ: u8 = 0x3a;
+    pub const WRITE: u8 = 0xa2;
+    pub const PWD_AUTH: u8 = 0x1b;
+    pub const READ_SIG: u8 = 0x3c;
+    pub const HLTA: u8 = 0x50;
+
+    pub const NAK_INVALID_ARGUMENT: u8 = 0x0;
+}
+
+const PAGE_SIZE_BYTES: usize = 4;
+const READ_PAGE_COUNT: usize = 4;
+const STATIC_LOCK_PAGE: usize = 2;
+const CAPABILITY_CONTAINER_PAGE: usize = 3;
+const PROT: u8 = 0x80;
+const SIGNATURE_LEN: usize = 32;
+
+/// NTAG21x answer to REQA and WUPA
+const ATQA: [u8; 2] = [0x44, 0x00];
+
+/// ISO/IEC 14443-3 PICC states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Ready,
+    Active,
+    Halt,
+}
+
+pub struct SimulatedReader {
+    pub pages: Vec<u8>,
+    pub present: bool,
+    /// Returned by READ_SIG, all zeros unless set
+    pub signature: Vec<u8>,
+    state: State,
+    authenticated: bool,
+    crc_errors: usize,
+    /// Page writes left before the tag leaves the field
+    writes_left: Option<usize>,
+}
+
+impl SimulatedReader {
+    /// Takes the complete memory of the tag, which needs to be a whole number of pages.
+    pub fn new(pages: Vec<u8>) -> Self {
+        assert!(pages.len().is_multiple_of(PAGE_SIZE_BYTES));
+        Self {
+            pages,
+            present: true,
+            signature: vec![0; SIGNATURE_LEN],
+            state: State::Idle,
+            authenticated: false,
+            crc_errors: 0,
+            writes_left: None,
+        }
+    }
+
+    /// Recreates a tag from a dump of a real one.
+    pub fn from_dump(dump: &TagDump) -> Result<Self, hex::FromHexError> {
+        let pages = dump
+            .pages
+            .iter()
+            .map(hex::decode)
+            .collect::<Result<Vec<_>, _>>()?;
+        if pages.iter().any(|page| page.len() != PAGE_SIZE_BYTES) {
+            return Err(hex::FromHexError::InvalidStringLength);
+        }
+        Ok(Self::new(pages.concat()))
+    }
+
+    /// Puts the tag back into the field, or takes it out, resetting its state either way.
+    pub fn set_present(&mut self, present: bool) {
+        self.present = present;
+        self.state = State::Idle;
+        self.authenticated = false;
+    }
+
+    /// Makes the next `count` responses fail their CRC check.
+    pub fn inject_crc_errors(&mut self, count: usize) {
+        self.crc_errors = count;
+    }
+
+    /// Takes the tag out of the field after the next `count` page writes.
+    pub fn remove_after_writes(&mut self, count: usize) {
+        self.writes_left = Some(count);
+    }
+
+    fn page_count(&self) -> usize {
+        self.pages.len() / PAGE_SIZE_BYTES
+    }
+
+    // the configuration pages are the last four
+    fn config_page(&self, offset: usize) -> &[u8] {
+        let start = (self.page_count() - 4 + offset) * PAGE_SIZE_BYTES;
+        &self.pages[start..start + PAGE_SIZE_BYTES]
+    }
+
+    fn auth0(&self) -> usize {
+        usize::from(self.config_page(0)[3])
+    }
+
+    fn password(&self) -> &[u8] {
+        self.config_page(2)
+    }
+
+    fn pack(&self) -> Vec<u8> {
+        self.config_page(3)[..2].to_vec()
+    }
+
+    /// Whether the password is needed to access `page`, for reading only with PROT set.
+    fn needs_password(&self, page: usize, write: bool) -> bool {
+        let protected = write || self.config_page(1)[0] & PROT != 0;
+        protected && page >= self.auth0() && !self.authenticated
+    }
+
+    fn is_locked(&self, page: usize) -> bool {
+        let static_lock = &self.pages[STATIC_LOCK_PAGE * PAGE_SIZE_BYTES + 2..];
+        let dynamic_lock = &self.pages[(self.page_count() - 5) * PAGE_SIZE_BYTES..];
+        let pages_per_bit = match self.page_count() {
+            45 => 2,
+            _ => 16,
+        };
+        match page {
+            3..=7 => static_lock[0] & (1 << page) != 0,
+            8..=15 => static_lock[1] & (1 << (page - 8)) != 0,
+            page if (16..self.page_count() - 5).contains(&page) => {
+                let bit = (page - 16) / pages_per_bit;
+                dynamic_lock[bit / 8] & (1 << (bit % 8)) != 0
+            }
+            _ => false,
+        }
+    }
+
+    fn write_page(&mut self, page: usize, data: &[u8]) -> Result<(), Error> {
+        if page < STATIC_LOCK_PAGE
+            || page >= self.page_count()
+            || self.is_locked(page)
+            || self.needs_password(page, true)
+        {
+            return Err(self.nak());
+        }
+
+        let start = page * PAGE_SIZE_BYTES;
+        let target = &mut self.pages[start..start + PAGE_SIZE_BYTES];
+        match page {
+            // the lock bytes are one-time programmable, the rest of the page is read-only
+            STATIC_LOCK_PAGE => {
+                target[2] |= data[2];
+                target[3] |= data[3];
+            }
+            CAPABILITY_CONTAINER_PAGE => target.iter_mut().zip(data).for_each(|(t, d)| *t |= d),
+            _ => target.copy_from_slice(data),
+        }
+        Ok(())
+    }
+
+    fn version(&self) -> Option<Vec<u8>> {
+        let storage_size = match self.page_count() {
+            45 => 0x0f,
+            135 => 0x11,
+            231 => 0x13,
+            _ => return None,
+        };
+        Some(vec![0x00, 0x04, 0x04, 0x02, 0x01, 0x00, storage_size, 0x03])
+    }
+
+    fn uid(&self) -> Vec<u8> {
+        // page 0 holds the first three UID bytes followed by a check byte
+        let mut uid = self.pages[0..3].to_vec();
+        uid.extend(&self.pages[4..8]);
+        uid
+    }
+
+    fn read_pages(&mut self, first: usize, last: usize) -> Result<Vec<u8>, Error> {
+        // READ rolls over to page 0 past the end of memory
+        let pages: Vec<usize> = (first..=last)
+            .map(|page| page % self.page_count())
+            .collect();
+        if pages.iter().any(|page| self.needs_password(*page, false)) {
+            return Err(self.nak());
+        }
+
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| match page >= self.page_count() - 2 {
+                // PWD and PACK always read back as zeros
+                true => vec![0; PAGE_SIZE_BYTES],
+                false => {
+                    let start = page * PAGE_SIZE_BYTES;
+                    self.pages[start..start + PAGE_SIZE_BYTES].to_vec()
+                }
+            })
+            .collect())
+    }
+
+    /// Like the real tag, anything unexpected sends it back to IDLE with a NAK.
+    fn nak(&mut self) -> Error {
+        self.state = State::Idle;
+        self.authenticated = false;
+        Error::Nak(command::NAK_INVALID_ARGUMENT)
+    }
+
+    fn respond(&mut self, tx: &[u8]) -> Result<Vec<u8>, Error> {
+        match *tx {
+            [command::GET_VERSION] => match self.version() {
+                Some(version) => Ok(version),
+                None => Err(self.nak()),
+            },
+            [command::READ, page] if usize::from(page) < self.page_count() => {
+                let page = usize::from(page);
+                self.read_pages(page, page + READ_PAGE_COUNT - 1)
+            }
+            [command::FAST_READ, first, last]
+                if first <= last && usize::from(last) < self.page_count() =>
+            {
+                self.read_pages(usize::from(first), usize::from(last))
+            }
+            [command::READ_SIG, 0x00] => Ok(self.signature.clone()),
+            [command::PWD_AUTH, ref password @ ..] if password == self.password() => {
+                self.authenticated = true;
+                Ok(self.pack())
+            }
+            _ => Err(self.nak()),
+        }
+    }
+}
+
+impl Reader for SimulatedReader {
+    fn reqa(&mut self) -> Result<AtqA, Error> {
+        match (self.present, self.state) {
+            (true, State::Idle) => {
+                self.state = State::Ready;
+                Ok(AtqA::new(ATQA))
+            }
+            (true, State::Ready | State::Active) => {
+                self.state = State::Idle;
+                Err(Error::Timeout)
+            }
+            _ => Err(Error::Timeout),
+        }
+    }
+
+    fn wupa(&mut self) -> Result<AtqA, Error> {
+        match (self.present, self.state) {
+            (true, State::Idle | State::Halt) => {
+                self.state = State::Ready;
+                Ok(AtqA::new(ATQA))
+            }
+            (true, _) => {
+                self.state = State::Idle;
+                Err(Error::Timeout)
+            }
+            _ => Err(Error::Timeout),
+        }
+    }
+
+    fn hlta(&mut self) -> Result<(), Error> {
+        if self.present && self.state == State::Active {
+            self.state = State::Halt;
+            self.authenticated = false;
+        }
+        Ok(())
+    }
+
+    fn select(&mut self, _atqa: &AtqA) -> Result<Uid, Error> {
+        match (self.present, self.state) {
+            (true, State::Ready) => {
+                self.state = State::Active;
+                Ok(Uid::new(self.uid(), 0x00))
+            }
+            _ => Err(Error::Timeout),
+        }
+    }
+
+    fn health_check(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.state = State::Idle;
+        self.authenticated = false;
+        Ok(())
+    }
+
+    fn transceive_crc(&mut self, tx: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.present || self.state != State::Active {
+            return Err(Error::Timeout);
+        }
+        if tx.first() == Some(&command::HLTA) {
+            return self.hlta().and(Err(Error::Timeout));
+        }
+
+        let response = self.respond(tx)?;
+        if self.crc_errors > 0 {
+            self.crc_errors -= 1;
+            return Err(Error::Crc);
+        }
+        Ok(response)
+    }
+
+    fn transceive_ack(&mut self, tx: &[u8]) -> Result<(), Error> {
+        if !self.present || self.state != State::Active {
+            return Err(Error::Timeout);
+        }
+
+        match *tx {
+            [command::WRITE, page, ref data @ ..] if data.len() == PAGE_SIZE_BYTES => {
+                self.write_page(usize::from(page), data)?;
+                match self.writes_left {
+                    Some(0) | Some(1) => {
+                        self.writes_left = None;
+                        self.set_present(false);
+                    }
+                    Some(count) => self.writes_left = Some(count - 1),
+                    None => {}
+                }
+                Ok(())
+            }
+            _ => Err(self.nak()),
+        }
+    }
+}
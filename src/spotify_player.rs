@@ -16,25 +16,131 @@ use librespot::{
         player::{Player, PlayerEvent},
     },
 };
+use rand::seq::SliceRandom;
 use sha1::{Digest, Sha1};
 use std::sync::Arc;
-use std::{collections::VecDeque, env};
+use std::time::Instant;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 use tracing::{error, info};
 use url::Url;
 
+use crate::json_store;
 use crate::player::Mixer;
+use crate::positions::Position;
 
 pub enum SpotifyPlayerCommand {
-    PlayTracks(Vec<SpotifyId>),
+    PlayTracks(Vec<SpotifyId>, Position),
+    Pause,
+    Resume,
     Stop,
+    Next,
+    /// Starts over at the end of the queue, kept until the next stop
+    Repeat(bool),
+    /// Shuffles the rest of the queue and anything played later on
+    Shuffle(bool),
+}
+
+/// The tracks being played and how far into the current one playback is.
+#[derive(Default)]
+struct Queue {
+    /// In album or playlist order, which resume positions refer to
+    tracks: Vec<SpotifyId>,
+    /// Indices into `tracks` in the order they get played
+    order: Vec<usize>,
+    index: usize,
+    position_ms: u32,
+    // set while playing, the position has moved on by the time elapsed since
+    playing_since: Option<Instant>,
+    repeat: bool,
+}
+
+impl Queue {
+    /// Starts at the track the position points at, followed by the rest in order or shuffled.
+    fn new(tracks: Vec<SpotifyId>, start: Position, shuffle: bool, repeat: bool) -> Self {
+        // the track list may have changed since the position was saved
+        let start = match start.track < tracks.len() {
+            true => start,
+            false => Position::default(),
+        };
+        let mut order: Vec<usize> = (0..tracks.len()).collect();
+        let index = match shuffle {
+            true => {
+                order.swap(0, start.track);
+                order[1..].shuffle(&mut rand::thread_rng());
+                0
+            }
+            false => start.track,
+        };
+
+        Self {
+            tracks,
+            order,
+            index,
+            position_ms: start.offset_ms as u32,
+            playing_since: None,
+            repeat,
+        }
+    }
+
+    fn current(&self) -> Option<SpotifyId> {
+        self.order.get(self.index).map(|&track| self.tracks[track])
+    }
+
+    fn upcoming(&self) -> Option<SpotifyId> {
+        self.order
+            .get(self.index + 1)
+            .map(|&track| self.tracks[track])
+    }
+
+    /// Shuffles the tracks after the current one, or goes back to the original order from the
+    /// current track on.
+    fn set_shuffle(&mut self, shuffle: bool) {
+        let Some(&current) = self.order.get(self.index) else {
+            return;
+        };
+        match shuffle {
+            true => self.order[self.index + 1..].shuffle(&mut rand::thread_rng()),
+            false => {
+                self.order = (0..self.tracks.len()).collect();
+                self.index = current;
+            }
+        }
+    }
+
+    fn update_position(&mut self, position_ms: u32, playing: bool) {
+        self.position_ms = position_ms;
+        self.playing_since = playing.then(Instant::now);
+    }
+
+    /// Moves on to the next track, returning it unless the end of the queue was reached.
+    fn advance(&mut self) -> Option<SpotifyId> {
+        self.index += 1;
+        if self.repeat && self.index >= self.order.len() {
+            self.index = 0;
+        }
+        self.update_position(0, false);
+        self.current()
+    }
+
+    fn position(&self) -> Option<Position> {
+        let track = *self.order.get(self.index)?;
+        let elapsed = self
+            .playing_since
+            .map(|since| since.elapsed().as_millis() as u64)
+            .unwrap_or(0);
+        Some(Position {
+            track,
+            offset_ms: self.position_ms as u64 + elapsed,
+        })
+    }
 }
 
 pub struct SpotifyPlayer {
     session: Arc<Mutex<Session>>,
     player_tx: UnboundedSender<SpotifyPlayerCommand>,
+    queue: Arc<Mutex<Queue>>,
 }
 
 impl SpotifyPlayer {
@@ -51,11 +157,16 @@ impl SpotifyPlayer {
             };
 
         let session = Arc::new(Mutex::new(session));
+        let queue = Arc::new(Mutex::new(Queue::default()));
 
         // TODO: consider keeping this around to enable us to check up on it
-        let _task = SpotifyPlayer::run(player, player_rx, player_event_receiver);
+        let _task = SpotifyPlayer::run(player, queue.clone(), player_rx, player_event_receiver);
 
-        let inst = Self { session, player_tx };
+        let inst = Self {
+            session,
+            player_tx,
+            queue,
+        };
 
         Ok(inst)
     }
@@ -80,37 +191,62 @@ impl SpotifyPlayer {
 
     fn run(
         player: Arc<Player>,
+        queue: Arc<Mutex<Queue>>,
         mut player_rx: UnboundedReceiver<SpotifyPlayerCommand>,
         mut player_event_receiver: UnboundedReceiver<PlayerEvent>,
     ) -> (JoinHandle<()>, JoinHandle<()>) {
-        let tracks: Arc<Mutex<VecDeque<SpotifyId>>> = Arc::new(Mutex::new(VecDeque::new()));
-        let tracks_command_handler = tracks.clone();
-        let tracks_event_handler = tracks.clone();
+        let queue_command_handler = queue.clone();
+        let queue_event_handler = queue.clone();
         let player_command = player.clone();
         let player_event = player.clone();
 
         (
             tokio::spawn(async move {
                 let player = player_command.clone();
+                let mut shuffle = false;
                 loop {
                     if let Some(command) = player_rx.recv().await {
-                        let mut tracks = tracks_command_handler.lock().await;
+                        let mut queue = queue_command_handler.lock().await;
                         match command {
-                            SpotifyPlayerCommand::PlayTracks(new_tracks) => {
-                                let first_track = new_tracks[0];
-                                let rest_tracks = new_tracks[1..].to_vec();
-
-                                // start playing the first track immediately
-                                player.load(first_track, true, 0);
-
-                                // queue up the other tracks
-                                tracks.extend(rest_tracks);
+                            SpotifyPlayerCommand::PlayTracks(tracks, start) => {
+                                *queue = Queue::new(tracks, start, shuffle, queue.repeat);
+                                if let Some(track) = queue.current() {
+                                    info!(
+                                        start.track,
+                                        queue.position_ms, "starting spotify playback"
+                                    );
+                                    player.load(track, true, queue.position_ms);
+                                }
+                            }
+                            SpotifyPlayerCommand::Pause => {
+                                info!("pausing spotify");
+                                player.pause();
+                            }
+                            SpotifyPlayerCommand::Resume => {
+                                info!("resuming spotify");
+                                player.play();
                             }
                             SpotifyPlayerCommand::Stop => {
                                 info!("stopping spotify");
-                                tracks.clear();
+                                *queue = Queue::default();
                                 player.stop();
                             }
+                            SpotifyPlayerCommand::Next => {
+                                info!("skipping to next track");
+                                match queue.advance() {
+                                    Some(next_track) => player.load(next_track, true, 0),
+                                    None => player.stop(),
+                                }
+                            }
+                            SpotifyPlayerCommand::Repeat(enabled) => {
+                                info!(enabled, "setting spotify repeat");
+                                queue.repeat = enabled;
+                            }
+                            SpotifyPlayerCommand::Shuffle(enabled) => {
+                                info!(enabled, "setting spotify shuffle");
+                                shuffle = enabled;
+                                queue.set_shuffle(shuffle);
+                            }
                         }
                     }
                 }
@@ -119,7 +255,7 @@ impl SpotifyPlayer {
                 let player = player_event.clone();
                 loop {
                     if let Some(player_event) = player_event_receiver.recv().await {
-                        let mut tracks = tracks_event_handler.lock().await;
+                        let mut queue = queue_event_handler.lock().await;
 
                         match player_event {
                             PlayerEvent::TimeToPreloadNextTrack {
@@ -127,9 +263,9 @@ impl SpotifyPlayer {
                                 track_id: _,
                             } => {
                                 info!("TimeToPreloadNextTrack!");
-                                if let Some(next_track) = tracks.front() {
+                                if let Some(next_track) = queue.upcoming() {
                                     info!(next_track.id, "pre-loading");
-                                    player.preload(next_track.to_owned());
+                                    player.preload(next_track);
                                 }
                             }
                             PlayerEvent::EndOfTrack {
@@ -137,11 +273,22 @@ impl SpotifyPlayer {
                                 track_id: _,
                             } => {
                                 info!("EndOfTrack!");
-                                if let Some(next_track) = tracks.pop_front() {
+                                if let Some(next_track) = queue.advance() {
                                     info!(next_track.id, "playing");
                                     player.load(next_track, true, 0);
                                 }
                             }
+                            PlayerEvent::Playing { position_ms, .. } => {
+                                queue.update_position(position_ms, true);
+                            }
+                            PlayerEvent::Paused { position_ms, .. } => {
+                                queue.update_position(position_ms, false);
+                            }
+                            PlayerEvent::PositionCorrection { position_ms, .. }
+                            | PlayerEvent::Seeked { position_ms, .. } => {
+                                let playing = queue.playing_since.is_some();
+                                queue.update_position(position_ms, playing);
+                            }
                             _ => {
                                 // TODO: implement more events?
                             }
@@ -160,10 +307,7 @@ impl SpotifyPlayer {
         let player_config = PlayerConfig::default();
         let audio_format = AudioFormat::default();
 
-        // we shouldn't need the default here, as systemd should export CACHE_DIRECTORY,
-        // but for some reason it is not seen by our process
-        let cache_directory =
-            env::var("CACHE_DIRECTORY").unwrap_or(String::from("/var/cache/drempelbox"));
+        let cache_directory = json_store::cache_directory();
 
         let cache = Cache::new(
             Some(cache_directory.clone() + "/credentials"),
@@ -198,7 +342,11 @@ impl SpotifyPlayer {
         Ok((session, player, receiver))
     }
 
-    pub async fn play_from_url(&mut self, url: Url) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn play_from_url(
+        &mut self,
+        url: Url,
+        start: Position,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         if let Some((context_type, spotify_id)) = url.path().trim_matches('/').split_once('/') {
             let mut spotify_id = SpotifyId::from_base62(spotify_id).unwrap();
             let session = self.session.lock().await;
@@ -206,21 +354,21 @@ impl SpotifyPlayer {
             match context_type {
                 "track" => {
                     spotify_id.item_type = SpotifyItemType::Track;
-                    self.play_tracks([spotify_id].iter()).await?;
+                    self.play_tracks([spotify_id].iter(), start).await?;
                     println!("Playing...");
                 }
                 "playlist" => {
                     let playlist: Playlist = Playlist::get(&session, &spotify_id).await.unwrap();
-                    self.play_tracks(playlist.tracks()).await?;
+                    self.play_tracks(playlist.tracks(), start).await?;
                 }
                 "album" => {
                     let album: Album = Album::get(&session, &spotify_id).await.unwrap();
-                    self.play_tracks(album.tracks()).await?;
+                    self.play_tracks(album.tracks(), start).await?;
                 }
                 "artist" => {
                     let artist: Artist = Artist::get(&session, &spotify_id).await.unwrap();
                     let top_tracks = artist.top_tracks.for_country("DE");
-                    self.play_tracks(top_tracks.iter()).await?;
+                    self.play_tracks(top_tracks.iter(), start).await?;
                 }
                 _ => info!("Unknown spotify context_type"),
             }
@@ -235,12 +383,115 @@ impl SpotifyPlayer {
         Ok(())
     }
 
-    async fn play_tracks<'a, T>(&self, tracks: T) -> Result<(), Box<dyn std::error::Error>>
+    pub async fn pause(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.player_tx.send(SpotifyPlayerCommand::Pause)?;
+        Ok(())
+    }
+
+    pub async fn resume(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.player_tx.send(SpotifyPlayerCommand::Resume)?;
+        Ok(())
+    }
+
+    pub async fn next(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.player_tx.send(SpotifyPlayerCommand::Next)?;
+        Ok(())
+    }
+
+    pub async fn set_repeat(&self, repeat: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.player_tx.send(SpotifyPlayerCommand::Repeat(repeat))?;
+        Ok(())
+    }
+
+    pub async fn set_shuffle(&self, shuffle: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.player_tx
+            .send(SpotifyPlayerCommand::Shuffle(shuffle))?;
+        Ok(())
+    }
+
+    /// Where playback currently is, `None` once the last track has finished.
+    pub async fn position(&self) -> Option<Position> {
+        self.queue.lock().await.position()
+    }
+
+    async fn play_tracks<'a, T>(
+        &self,
+        tracks: T,
+        start: Position,
+    ) -> Result<(), Box<dyn std::error::Error>>
     where
         T: Iterator<Item = &'a SpotifyId>,
     {
+        let tracks: Vec<SpotifyId> = tracks.cloned().collect();
+        if tracks.is_empty() {
+            return Err(Box::<dyn std::error::Error>::from("no tracks to play"));
+        }
         self.player_tx
-            .send(SpotifyPlayerCommand::PlayTracks(tracks.cloned().collect()))?;
+            .send(SpotifyPlayerCommand::PlayTracks(tracks, start))?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::spotify_player::*;
+    use librespot::core::spotify_id::SpotifyItemType;
+
+    fn tracks(count: u128) -> Vec<SpotifyId> {
+        (0..count)
+            .map(|id| SpotifyId {
+                id,
+                item_type: SpotifyItemType::Track,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resume_shuffled_queue() {
+        let mut queue = Queue::new(tracks(20), Position::default(), true, false);
+        queue.advance();
+        queue.advance();
+        queue.update_position(1500, false);
+        let playing = queue.current();
+        let saved = queue.position().unwrap();
+        assert_eq!(Some(tracks(20)[saved.track]), playing);
+
+        // the saved track comes first, whether shuffled again or not
+        for shuffle in [true, false] {
+            let resumed = Queue::new(tracks(20), saved, shuffle, false);
+            assert_eq!(resumed.current(), playing);
+            assert_eq!(resumed.position(), Some(saved));
+        }
+
+        // a start offset doesn't keep the rest of the card from being shuffled
+        let start = Position {
+            track: 0,
+            offset_ms: 90_000,
+        };
+        let queue = Queue::new(tracks(20), start, true, false);
+        assert_eq!(queue.position(), Some(start));
+        let mut order = queue.order.clone();
+        order.sort();
+        assert_eq!(order, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn toggle_shuffle() {
+        let mut queue = Queue::new(tracks(20), Position::default(), false, false);
+        queue.advance();
+        queue.set_shuffle(true);
+        assert_eq!(queue.current(), Some(tracks(20)[1]));
+        assert_eq!(queue.position().unwrap().track, 1);
+
+        queue.advance();
+        let playing = queue.current().unwrap();
+        let track = queue.position().unwrap().track;
+        assert_eq!(tracks(20)[track], playing);
+
+        // back in album order from the track that is playing
+        queue.set_shuffle(false);
+        assert_eq!(queue.current(), Some(playing));
+        assert_eq!(queue.position().unwrap().track, track);
+        assert_eq!(queue.upcoming(), tracks(20).get(track + 1).copied());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::ndef::Record;
+use crate::ntag21x::TagType;
+use serde::{Serialize, Serializer};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// How far a subscriber can fall behind before it starts missing events
+const CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagEventKind {
+    Added,
+    Removed,
+}
+
+/// A tag showing up on or leaving the reader, for anything that wants to know without
+/// hooking into the reader task.
+#[derive(Debug, Clone, Serialize)]
+pub struct TagEvent {
+    pub kind: TagEventKind,
+    #[serde(serialize_with = "serialize_hex")]
+    pub uid: Vec<u8>,
+    /// Only known for added tags that could be identified
+    pub tag_type: Option<TagType>,
+    /// Only known for added tags with a valid NDEF message
+    pub records: Option<Vec<Record>>,
+    /// Milliseconds since the unix epoch on the wire
+    #[serde(serialize_with = "serialize_unix_millis")]
+    pub timestamp: SystemTime,
+}
+
+impl TagEvent {
+    pub fn added(uid: &[u8], tag_type: Option<TagType>, records: Option<Vec<Record>>) -> Self {
+        Self {
+            kind: TagEventKind::Added,
+            uid: uid.to_vec(),
+            tag_type,
+            records,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn removed(uid: &[u8]) -> Self {
+        Self {
+            kind: TagEventKind::Removed,
+            uid: uid.to_vec(),
+            tag_type: None,
+            records: None,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+fn serialize_unix_millis<S: Serializer>(
+    time: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0);
+    serializer.serialize_u64(millis)
+}
+
+/// Subscribe through `broadcast::Sender::subscribe`, there are no receivers to begin with.
+pub fn channel() -> broadcast::Sender<TagEvent> {
+    broadcast::channel(CAPACITY).0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tag_events::*;
+    use std::time::Duration;
+
+    #[test]
+    fn serialize_event() {
+        let mut event = TagEvent::removed(&[0x04, 0x8a, 0x3b, 0x12, 0x5c, 0x61, 0x80]);
+        event.timestamp = UNIX_EPOCH + Duration::from_millis(1_760_000_000_123);
+
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "kind": "removed",
+                "uid": "048a3b125c6180",
+                "tag_type": null,
+                "records": null,
+                "timestamp": 1_760_000_000_123u64,
+            })
+        );
+    }
+}
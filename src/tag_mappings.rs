@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::json_store;
+
+/// Media assigned to a tag by its UID, so blank cards and figurines work without NDEF content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagMapping {
+    pub url: String,
+    pub title: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct TagMappings {
+    path: PathBuf,
+    // keyed by the lowercase hex UID, a BTreeMap keeps the file stable between saves
+    mappings: Arc<Mutex<BTreeMap<String, TagMapping>>>,
+}
+
+impl TagMappings {
+    const FILE_NAME: &'static str = "tag_mappings.json";
+
+    /// Loads the mappings from the cache directory, starting empty if there are none yet.
+    pub async fn load() -> Self {
+        let path = json_store::cache_path(Self::FILE_NAME);
+        let mappings: BTreeMap<String, TagMapping> = json_store::load(&path).await;
+        info!(count = mappings.len(), "loaded tag mappings");
+
+        TagMappings {
+            path,
+            mappings: Arc::new(Mutex::new(mappings)),
+        }
+    }
+
+    pub async fn get(&self, uid: &[u8]) -> Option<TagMapping> {
+        self.mappings.lock().await.get(&hex::encode(uid)).cloned()
+    }
+
+    pub async fn all(&self) -> BTreeMap<String, TagMapping> {
+        self.mappings.lock().await.clone()
+    }
+
+    pub async fn set(&self, uid: &[u8], mapping: TagMapping) -> io::Result<()> {
+        let mut mappings = self.mappings.lock().await;
+        mappings.insert(hex::encode(uid), mapping);
+        self.save(&mappings).await
+    }
+
+    /// Returns whether there was a mapping for the UID.
+    pub async fn remove(&self, uid: &[u8]) -> io::Result<bool> {
+        let mut mappings = self.mappings.lock().await;
+        if mappings.remove(&hex::encode(uid)).is_none() {
+            return Ok(false);
+        }
+        self.save(&mappings).await.map(|_| true)
+    }
+
+    async fn save(&self, mappings: &BTreeMap<String, TagMapping>) -> io::Result<()> {
+        json_store::save(&self.path, mappings).await
+    }
+}